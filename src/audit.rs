@@ -0,0 +1,86 @@
+use log::{debug, warn};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::report::ErrorClass;
+use crate::ssh::{AuthMethod, PasswordSource};
+
+/// A connection as recorded in the audit log, passwords are never included
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    /// When the connection started
+    pub timestamp: String,
+    pub local_user: String,
+    pub subcommand: String,
+    pub target: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub auth: Option<AuthMethod>,
+    pub password_source: Option<PasswordSource>,
+    pub success: bool,
+    pub error: Option<ErrorClass>,
+    pub message: Option<String>,
+    /// Seconds the connection lasted
+    pub duration: f64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<String>,
+}
+
+impl Entry {
+    pub fn new(subcommand: &str, target: &str, started: SystemTime) -> Self {
+        Self {
+            timestamp: chrono::DateTime::<chrono::Local>::from(started).to_rfc3339(),
+            local_user: local_user(),
+            subcommand: subcommand.to_string(),
+            target: target.to_string(),
+            user: None,
+            port: None,
+            auth: None,
+            password_source: None,
+            success: false,
+            error: None,
+            message: None,
+            duration: 0.0,
+            commands: Vec::new(),
+        }
+    }
+}
+
+/// Name of the local user, or its uid when the environment does not tell
+fn local_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .ok()
+        .filter(|x| !x.is_empty())
+        .or_else(|| fs::metadata("/proc/self").ok().map(|x| x.uid().to_string()))
+        .unwrap_or_default()
+}
+
+/// Append an entry to the audit log in the state directory, failures are only
+/// warned about so they never stop a connection
+pub fn write(state: &Path, entry: &Entry) {
+    let path = state.join("audit.jsonl");
+    let result = serde_json::to_string(entry)
+        .map_err(std::io::Error::from)
+        .and_then(|line| {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(state)?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(&path)?;
+            // a single write keeps lines of concurrent processes whole
+            file.write_all(format!("{line}\n").as_bytes())
+        });
+    match result {
+        Ok(()) => debug!("audit entry written to {path:?}"),
+        Err(error) => warn!("unable to write audit log {path:?}: {error}"),
+    }
+}
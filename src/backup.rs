@@ -0,0 +1,184 @@
+use anyhow::{anyhow, bail, Ok};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{fs, path::PathBuf};
+
+use crate::cache::{CacheKey, CachedPassword, PasswordCache};
+use crate::config::ConfigDirs;
+use crate::credentials::{self, Credentials};
+use crate::encryption;
+
+const BACKUP_VERSION: u32 = 1;
+
+/// How to handle entries that already exist when restoring a backup
+#[derive(Debug, Clone, Copy, Default, ValueEnum, PartialEq)]
+pub enum Conflict {
+    /// Keep existing entries
+    Keep,
+    /// Replace existing entries with the backup ones
+    Overwrite,
+    /// Add missing passwords to existing credentials, keep the newest cached passwords and
+    /// merge the configuration, the backup values replacing existing ones
+    #[default]
+    Merge,
+}
+
+/// Versioned archive of the asd store, written encrypted as a single file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub created: DateTime<Local>,
+    #[serde(default)]
+    pub config: Option<String>,
+    #[serde(default)]
+    pub credentials: BTreeMap<String, Credentials>,
+    #[serde(default)]
+    pub cache: Option<BTreeMap<String, CachedPassword>>,
+}
+
+impl Backup {
+    pub fn create(
+        passphrase: &str,
+        dirs: &ConfigDirs,
+        include_cache: bool,
+    ) -> anyhow::Result<Self> {
+        let config_path = dirs.config.join("config.toml");
+        let config = if config_path.exists() {
+            Some(fs::read_to_string(&config_path)?)
+        } else {
+            None
+        };
+        let credentials_dir = dirs.data.join("credentials");
+        let mut credentials = BTreeMap::new();
+        for user in Credentials::users(&credentials_dir)? {
+            debug!("adding {user} credentials to backup");
+            let file = credentials_dir.join(&user);
+            credentials.insert(user, Credentials::load(passphrase, &file)?);
+        }
+        let cache = if include_cache {
+            let cache = PasswordCache::open(passphrase, &dirs.state)?;
+            Some(
+                cache
+                    .entries()
+                    .into_iter()
+                    .map(|(key, cached)| (key.to_string(), cached.clone()))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        Ok(Self {
+            version: BACKUP_VERSION,
+            created: Local::now(),
+            config,
+            credentials,
+            cache,
+        })
+    }
+
+    pub fn save(&self, passphrase: &str, file: &PathBuf) -> anyhow::Result<()> {
+        encryption::encrypt(passphrase, toml::to_string(self)?.as_bytes(), file)
+    }
+
+    pub fn load(passphrase: &str, file: &PathBuf) -> anyhow::Result<Self> {
+        let backup = toml::from_str::<Self>(&encryption::decrypt(passphrase, file)?)?;
+        if backup.version > BACKUP_VERSION {
+            bail!(anyhow!(
+                "backup version {} is newer than the supported version {BACKUP_VERSION}",
+                backup.version
+            ));
+        }
+        debug!(
+            "loaded backup version {} from {}",
+            backup.version, backup.created
+        );
+        Ok(backup)
+    }
+
+    /// Restore the backup, re-encrypting its contents with `passphrase`
+    pub fn restore(
+        self,
+        passphrase: &str,
+        dirs: &ConfigDirs,
+        conflict: Conflict,
+    ) -> anyhow::Result<()> {
+        // users name the credential files, check them all before writing anything
+        for user in self.credentials.keys() {
+            credentials::check_user(user).map_err(|error| anyhow!("{error} in backup"))?;
+        }
+        if let Some(config) = self.config {
+            let path = dirs.config.join("config.toml");
+            let config = match conflict {
+                _ if !path.exists() => Some(config),
+                Conflict::Overwrite => Some(config),
+                Conflict::Merge => {
+                    let mut existing = toml::from_str::<toml::Table>(&fs::read_to_string(&path)?)?;
+                    merge_table(&mut existing, toml::from_str(&config)?);
+                    Some(toml::to_string(&existing)?)
+                }
+                Conflict::Keep => None,
+            };
+            if let Some(config) = config {
+                info!("restoring configuration");
+                fs::create_dir_all(&dirs.config)?;
+                fs::write(&path, config)?;
+            } else {
+                info!("keeping existing configuration");
+            }
+        }
+        let credentials_dir = dirs.data.join("credentials");
+        for (user, restored) in self.credentials {
+            let file = credentials_dir.join(&user);
+            let credentials = if !file.exists() || conflict == Conflict::Overwrite {
+                restored
+            } else if conflict == Conflict::Merge {
+                let mut credentials = Credentials::load(passphrase, &file)?;
+                for credential in restored.passwords {
+                    credentials.merge(credential);
+                }
+                credentials
+            } else {
+                info!("keeping existing {user} credentials");
+                continue;
+            };
+            info!("restoring {user} credentials");
+            credentials.save(passphrase, &file)?;
+        }
+        if let Some(restored) = self.cache {
+            let mut cache = PasswordCache::open(passphrase, &dirs.state)?;
+            for (name, cached) in restored {
+                let Some(key) = CacheKey::parse(&name) else {
+                    continue;
+                };
+                let replace = match (cache.get(&key), conflict) {
+                    (None, _) | (_, Conflict::Overwrite) => true,
+                    (Some(existing), Conflict::Merge) => existing.created < cached.created,
+                    (Some(_), Conflict::Keep) => false,
+                };
+                if replace {
+                    debug!("restoring cached password for {key}");
+                    cache.insert(key, cached);
+                }
+            }
+            cache.save(passphrase)?;
+        }
+        Ok(())
+    }
+}
+
+/// Merge `other` into `table` recursively, values of `other` win
+fn merge_table(table: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => {
+                merge_table(existing, value)
+            }
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use anyhow::{anyhow, bail, Ok};
+use chrono::{DateTime, Local};
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::encryption;
+
+/// Remote identifying a cached password, formatted as `user@host:port`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CacheKey {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl CacheKey {
+    pub fn new(user: impl Into<String>, host: impl Into<String>, port: u16) -> Self {
+        Self {
+            user: user.into(),
+            host: host.into(),
+            port,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        let (user, remote) = name.split_once('@')?;
+        let (host, port) = remote.rsplit_once(':')?;
+        Some(Self::new(user, host, port.parse().ok()?))
+    }
+
+    /// Match a glob against the key, patterns without `@` only match the host
+    pub fn matches(&self, pattern: &glob::Pattern) -> bool {
+        if pattern.as_str().contains('@') {
+            pattern.matches(&self.to_string())
+        } else {
+            pattern.matches(&self.host)
+        }
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedPassword {
+    pub password: String,
+    #[serde(default = "Local::now")]
+    pub created: DateTime<Local>,
+}
+
+impl CachedPassword {
+    pub fn new(password: impl Into<String>) -> Self {
+        Self {
+            password: password.into(),
+            created: Local::now(),
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        (Local::now() - self.created).to_std().unwrap_or_default()
+    }
+
+    pub fn is_expired(&self, expire_time: Duration) -> bool {
+        self.age() > expire_time
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheData {
+    #[serde(default)]
+    passwords: BTreeMap<String, CachedPassword>,
+}
+
+/// Encrypted password cache stored in a single file and decrypted once per
+/// invocation. Writes are atomic and serialized between concurrent runs with a
+/// lock file, changes are merged with the current file contents on save.
+#[derive(Debug)]
+pub struct PasswordCache {
+    path: PathBuf,
+    lock_path: PathBuf,
+    passwords: HashMap<CacheKey, CachedPassword>,
+    hosts: HashMap<String, Vec<CacheKey>>,
+    inserted: BTreeSet<CacheKey>,
+    removed: BTreeSet<CacheKey>,
+    legacy: Vec<PathBuf>,
+    loaded: Option<SystemTime>,
+}
+
+impl PasswordCache {
+    pub fn open(passphrase: &str, dir: &PathBuf) -> anyhow::Result<Self> {
+        let mut cache = Self {
+            path: dir.join("cache.gpg"),
+            lock_path: dir.join("cache.lock"),
+            passwords: HashMap::new(),
+            hosts: HashMap::new(),
+            inserted: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            legacy: Vec::new(),
+            loaded: None,
+        };
+        if cache.path.exists() {
+            let lock = cache.lock_file()?;
+            lock.lock_shared()?;
+            cache.loaded = Some(cache.path.metadata()?.modified()?);
+            for (key, password) in cache.read(passphrase)? {
+                cache.index(key, password);
+            }
+        }
+        cache.migrate(passphrase, dir)?;
+        Ok(cache)
+    }
+
+    fn lock_file(&self) -> anyhow::Result<File> {
+        if let Some(parent) = self.lock_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock_path)?)
+    }
+
+    fn read(&self, passphrase: &str) -> anyhow::Result<Vec<(CacheKey, CachedPassword)>> {
+        debug!("decrypting password cache: {:?}", self.path);
+        let data = toml::from_str::<CacheData>(&encryption::decrypt(passphrase, &self.path)?)?;
+        Ok(data
+            .passwords
+            .into_iter()
+            .filter_map(|(name, password)| match CacheKey::parse(&name) {
+                Some(key) => Some((key, password)),
+                None => {
+                    warn!("ignoring invalid cache entry {name:?}");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn index(&mut self, key: CacheKey, password: CachedPassword) {
+        let keys = self.hosts.entry(key.host.clone()).or_default();
+        if !keys.contains(&key) {
+            keys.push(key.clone());
+        }
+        self.passwords.insert(key, password);
+    }
+
+    /// Import passwords cached as one `user@host:port` file each
+    fn migrate(&mut self, passphrase: &str, dir: &PathBuf) -> anyhow::Result<()> {
+        if !dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)?.filter_map(|x| x.ok()) {
+            let path = entry.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(CacheKey::parse)
+            else {
+                continue;
+            };
+            debug!("migrating legacy cache file: {path:?}");
+            let read = || {
+                let created = path.metadata()?.modified()?.into();
+                Ok((encryption::decrypt(passphrase, &path)?, created))
+            };
+            // unreadable files are left in place so they are not lost
+            let (password, created) = match read() {
+                Result::Ok(read) => read,
+                Err(error) => {
+                    warn!("skipping legacy cache file {path:?}: {error}");
+                    continue;
+                }
+            };
+            self.insert(key, CachedPassword { password, created });
+            self.legacy.push(path);
+        }
+        if !self.legacy.is_empty() {
+            self.save(passphrase)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<&CachedPassword> {
+        self.passwords.get(key)
+    }
+
+    /// Cached remotes for a host in insertion order
+    pub fn keys_for_host(&self, host: &str) -> &[CacheKey] {
+        self.hosts
+            .get(host)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Cached passwords sorted by host, user and port
+    pub fn entries(&self) -> Vec<(&CacheKey, &CachedPassword)> {
+        let mut entries = self.passwords.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            (&a.0.host, &a.0.user, a.0.port).cmp(&(&b.0.host, &b.0.user, b.0.port))
+        });
+        entries
+    }
+
+    pub fn insert(&mut self, key: CacheKey, password: CachedPassword) {
+        self.removed.remove(&key);
+        self.inserted.insert(key.clone());
+        self.index(key, password);
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) -> Option<CachedPassword> {
+        let password = self.passwords.remove(key)?;
+        if let Some(keys) = self.hosts.get_mut(&key.host) {
+            keys.retain(|x| x != key);
+        }
+        self.inserted.remove(key);
+        self.removed.insert(key.clone());
+        Some(password)
+    }
+
+    /// Write pending changes, merging them with entries saved by other runs
+    pub fn save(&mut self, passphrase: &str) -> anyhow::Result<()> {
+        if self.inserted.is_empty() && self.removed.is_empty() {
+            debug!("password cache unchanged");
+            return Ok(());
+        }
+        let lock = self.lock_file()?;
+        lock.lock()?;
+        let modified = self.path.metadata().ok().and_then(|x| x.modified().ok());
+        let mut passwords = if modified.is_some() && modified != self.loaded {
+            debug!("password cache changed on disk, merging changes");
+            self.read(passphrase)?
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        } else {
+            self.passwords
+                .iter()
+                .filter(|(key, _)| !self.inserted.contains(key))
+                .map(|(key, password)| (key.clone(), password.clone()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        for key in &self.removed {
+            passwords.remove(key);
+        }
+        for key in &self.inserted {
+            passwords.insert(key.clone(), self.passwords[key].clone());
+        }
+        let data = CacheData {
+            passwords: passwords
+                .iter()
+                .map(|(key, password)| (key.to_string(), password.clone()))
+                .collect(),
+        };
+        trace!("writing {} cached passwords", data.passwords.len());
+        let tmp = self.path.with_extension("gpg.tmp");
+        encryption::encrypt(passphrase, toml::to_string(&data)?.as_bytes(), &tmp)?;
+        fs::rename(&tmp, &self.path)?;
+        self.loaded = Some(self.path.metadata()?.modified()?);
+        self.passwords.clear();
+        self.hosts.clear();
+        for (key, password) in passwords {
+            self.index(key, password);
+        }
+        self.inserted.clear();
+        self.removed.clear();
+        for path in self.legacy.drain(..) {
+            debug!("removing migrated cache file: {path:?}");
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse durations like `90s`, `30m`, `12h`, `7d` or `1w`, seconds if no unit is given
+pub fn parse_duration(value: &str) -> anyhow::Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|x: char| !x.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| anyhow!("invalid duration {value:?}"))?;
+    let seconds = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        unit => bail!(anyhow!("invalid duration unit {unit:?} in {value:?}")),
+    };
+    let seconds = number
+        .checked_mul(seconds)
+        .ok_or_else(|| anyhow!("duration {value:?} too large"))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Format a duration with its two most significant units, e.g. `3h12m`
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let units = [(60 * 60 * 24, "d"), (60 * 60, "h"), (60, "m"), (1, "s")];
+    let parts = units
+        .iter()
+        .scan(seconds, |rest, (size, unit)| {
+            let value = *rest / size;
+            *rest %= size;
+            Some((value, unit))
+        })
+        .skip_while(|(value, _)| *value == 0)
+        .take(2)
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect::<String>();
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration(" 5m ").unwrap(), Duration::from_secs(300));
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(1_209_600)
+        );
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn duration_too_large() {
+        let error = parse_duration("99999999999999999w").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "duration \"99999999999999999w\" too large"
+        );
+    }
+}
@@ -1,3 +1,11 @@
+use crate::backup::Conflict;
+use crate::environment;
+use crate::escalation::BecomeMethod;
+use crate::forward::{self, Forward};
+use crate::import::ImportFormat;
+use crate::report::Format;
+use crate::ssh::Multiplexer;
+use crate::transfer::Links;
 use clap::{ArgGroup, Args, Subcommand};
 use std::path::PathBuf;
 use strum::{Display, EnumIter};
@@ -13,19 +21,36 @@ pub struct Parser {
 pub enum CommandEnum {
     /// Open SSH connection to given remote [default]
     Ssh(ConnectionArgs),
-    /// Open SFTP connection to given remote
+    /// Open SFTP connection to given remote (not implemented yet)
     Sftp(ConnectionArgs),
     /// Execute script or commands on listed remotes
     Exec(ExecuteArgs),
     /// Send list of files to the specified remotes
-    Put(FileArgs),
+    Put(PutArgs),
     /// Get list of files to the specified remotes
     Get(FileArgs),
     /// Execute ansible playbook using asd password detection
     Book(PlaybookArgs),
+    /// Manage cached remote passwords
+    #[command(subcommand)]
+    Cache(CacheEnum),
     /// Configure application
     #[command(subcommand)]
     Config(ConfigEnum),
+    /// Play a recorded session
+    Replay {
+        /// Recording to play, an asciicast v2 file
+        file: PathBuf,
+        /// Playback speed multiplier
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f64,
+        /// Cap pauses between outputs to this many seconds
+        #[arg(short, long, value_name = "SECONDS")]
+        idle_limit: Option<f64>,
+    },
+    /// Serve a master connection, started by asd itself
+    #[command(name = "controlmaster", hide = true)]
+    ControlMaster { socket: PathBuf },
 }
 
 #[derive(Debug, Args, Default)]
@@ -38,6 +63,62 @@ pub struct ConnectionArgs {
     /// Port to use for the connection
     #[arg(short, long)]
     pub port: Option<u16>,
+    /// Connect through the given jump hosts, comma separated [user@]host[:port] list,
+    /// stored passwords are tried on each hop as on the remote, see
+    /// 'asd config credentials add --help'
+    #[arg(short = 'J', long, value_name = "DESTINATION")]
+    pub jump: Option<String>,
+    /// Forward a local port to the given host and port from the remote
+    #[arg(
+        short = 'L',
+        long,
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = forward::parse_local
+    )]
+    pub local_forward: Vec<Forward>,
+    /// Forward a remote port to the given host and port from this machine
+    #[arg(
+        short = 'R',
+        long,
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = forward::parse_remote
+    )]
+    pub remote_forward: Vec<Forward>,
+    /// Listen on a local port as a SOCKS5 proxy connecting through the remote
+    #[arg(
+        short = 'D',
+        long,
+        value_name = "[BIND:]PORT",
+        value_parser = forward::parse_dynamic
+    )]
+    pub dynamic_forward: Vec<Forward>,
+    /// Do not open a shell, only forward ports
+    #[arg(short = 'N', long)]
+    pub no_command: bool,
+    /// Set an environment variable in the remote
+    #[arg(short, long, value_name = "KEY=VALUE", value_parser = environment::parse)]
+    pub env: Vec<(String, String)>,
+    /// Record the session output under the data directory, see `asd replay`
+    #[arg(long)]
+    pub record: bool,
+    /// Reuse or start a background master connection to the remote
+    #[arg(short = 'M', long)]
+    pub master: bool,
+    /// Reconnect with the cached password and reopen the shell when the
+    /// connection is lost, giving up after ATTEMPTS failed attempts in a row
+    #[arg(
+        long,
+        value_name = "ATTEMPTS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10",
+        conflicts_with = "no_command"
+    )]
+    pub reconnect: Option<u32>,
+    /// Open the shell in a session of the given multiplexer, attaching to it if
+    /// it is already running
+    #[arg(long, value_name = "MULTIPLEXER", conflicts_with = "no_command")]
+    pub attach: Option<Multiplexer>,
     /// Ask for connection password
     #[arg(short = 'k', long, conflicts_with = "cache", conflicts_with = "force")]
     pub ask_pass: bool,
@@ -80,12 +161,39 @@ pub struct ExecuteArgs {
     /// Args to be passed to remote script
     #[clap(short, long, allow_hyphen_values = true, requires = "exec")]
     pub args: Option<Vec<String>>,
+    /// Program to run the script with instead of the one in its shebang line,
+    /// e.g. python3
+    #[arg(long, value_name = "PROGRAM", requires = "exec")]
+    pub interpreter: Option<String>,
+    /// Send the script through stdin instead of uploading it, for remotes
+    /// without a writable /tmp
+    #[arg(long, requires = "exec")]
+    pub stdin: bool,
     /// Commands to send to the remote
     #[arg(short, long, allow_hyphen_values = true, group = "required")]
     pub commands: Option<Vec<String>>,
     /// Do not connect to the remote; merely test the connection
     #[arg(short = 'u', long, group = "required")]
     pub dry_run: bool,
+    /// Login user to use for the connections
+    #[arg(short, long)]
+    pub login_name: Option<String>,
+    /// Set an environment variable in the remotes
+    #[arg(short, long, value_name = "KEY=VALUE", value_parser = environment::parse)]
+    pub env: Vec<(String, String)>,
+    #[command(flatten)]
+    pub runner: RunnerArgs,
+    #[command(flatten)]
+    pub escalation: BecomeArgs,
+    /// Print each host complete output once it finishes instead of prefixed lines
+    #[arg(long)]
+    pub tree: bool,
+    /// Print each distinct output once with the list of hosts that produced it
+    #[arg(long, conflicts_with = "tree")]
+    pub collapse: bool,
+    /// Also write the output of each host to <DIR>/<host>.stdout and <DIR>/<host>.stderr
+    #[arg(long, value_name = "DIR")]
+    pub out_dir: Option<PathBuf>,
     /// Quiet mode. Causes most warning and diagnostic messages to be suppressed
     #[arg(short, long, conflicts_with = "verbose")]
     pub quiet: bool,
@@ -99,8 +207,44 @@ pub struct FileArgs {
     /// Specify inventory host path or comma separated host list
     #[arg(short, long)]
     pub inventory: String,
-    /// Files to send to the remotes
+    /// Files to transfer, local files for put and remote files for get
+    #[arg(required = true)]
     pub files: Vec<PathBuf>,
+    /// Destination directory, in the remotes for put and locally for get
+    #[arg(short, long, value_name = "DIR")]
+    pub dest: Option<String>,
+    /// Transfer directories recursively
+    #[arg(short, long)]
+    pub recursive: bool,
+    /// Skip files whose size and modification time match the destination
+    #[arg(long)]
+    pub skip_unchanged: bool,
+    /// Compare files by SHA-256 instead of modification time, needs sha256sum in the remotes
+    #[arg(short, long, requires = "skip_unchanged")]
+    pub checksum: bool,
+    /// Delete destination files missing from the transferred directories
+    #[arg(long, requires = "recursive")]
+    pub delete: bool,
+    /// Check the SHA-256 of each transferred file before moving it into place,
+    /// needs sha256sum in the remotes
+    #[arg(long)]
+    pub verify: bool,
+    /// Transfer paths matching the glob even if excluded
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+    /// Skip paths matching the glob, matched against the relative path and the file name
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// How to transfer symbolic links
+    #[arg(long, value_enum, default_value_t)]
+    pub links: Links,
+    /// Login user to use for the connections
+    #[arg(short, long)]
+    pub login_name: Option<String>,
+    #[command(flatten)]
+    pub runner: RunnerArgs,
+    #[command(flatten)]
+    pub escalation: BecomeArgs,
     /// Quiet mode. Causes most warning and diagnostic messages to be suppressed
     #[arg(short, long, conflicts_with = "verbose")]
     pub quiet: bool,
@@ -109,6 +253,49 @@ pub struct FileArgs {
     pub verbose: bool,
 }
 
+#[derive(Debug, Args, Default)]
+pub struct PutArgs {
+    #[command(flatten)]
+    pub file: FileArgs,
+    /// Render files replacing `{{ var }}` with the inventory variables of each
+    /// host, `{{ host }}` is the host name. Files that are not UTF-8 are sent as is
+    #[arg(long, conflicts_with = "skip_unchanged")]
+    pub template: bool,
+    /// Only render files matching the glob, matched against the relative path and
+    /// the file name, others are sent as is
+    #[arg(long, value_name = "GLOB", requires = "template")]
+    pub template_glob: Vec<String>,
+}
+
+#[derive(Debug, Args, Default)]
+pub struct RunnerArgs {
+    /// Number of hosts to run in parallel [default: config forks]
+    #[arg(short, long)]
+    pub forks: Option<usize>,
+    /// Time limit for each host, e.g. 90s or 5m [default: config host_timeout]
+    #[arg(short, long)]
+    pub timeout: Option<String>,
+    /// Print results in inventory order once every host finished
+    #[arg(long)]
+    pub ordered: bool,
+    /// Format of the results
+    #[arg(short, long, value_enum, default_value_t)]
+    pub output: Format,
+}
+
+#[derive(Debug, Clone, Args, Default)]
+pub struct BecomeArgs {
+    /// Run commands and write files as another user
+    #[arg(short = 'b', long = "become")]
+    pub enabled: bool,
+    /// User to become
+    #[arg(long, value_name = "USER", default_value = "root")]
+    pub become_user: String,
+    /// How to become the user
+    #[arg(long, value_enum, default_value_t)]
+    pub become_method: BecomeMethod,
+}
+
 #[derive(Debug, Args, Default)]
 pub struct PlaybookArgs {
     /// Specify inventory host path or comma separated host list
@@ -124,18 +311,120 @@ pub enum ConfigEnum {
     /// Initialize configuration and create neccesary folders
     #[default]
     Init,
-    /// Create/modify specified credentials
-    Credentials { 
-        /// User to modify credentials to, will ask for user if not specified
-        user: Option<String>
+    /// Manage stored user credentials
+    #[command(subcommand)]
+    Credentials(CredentialsEnum),
+    /// Import credentials from a password manager export
+    Import {
+        /// Format of the source to import
+        #[arg(short, long, value_enum)]
+        from: ImportFormat,
+        /// Password store directory or exported file
+        source: PathBuf,
+        /// User for entries without a login user, defaults to the configured login user
+        #[arg(short, long)]
+        login_name: Option<String>,
+        /// Show what would be imported without saving credentials
+        #[arg(short = 'u', long)]
+        dry_run: bool,
+    },
+    /// Export configuration, credentials and optionally the password cache to an encrypted file
+    Export {
+        /// Path of the encrypted backup to create
+        file: PathBuf,
+        /// Include cached remote passwords
+        #[arg(short, long)]
+        cache: bool,
+    },
+    /// Restore a backup created with 'asd config export'
+    ImportBackup {
+        /// Path of the encrypted backup
+        file: PathBuf,
+        /// How to handle configuration, credentials and cached passwords that already exist
+        #[arg(short, long, value_enum, default_value_t)]
+        conflict: Conflict,
     },
     /// Set/change passphrase
     Passphrase,
     /// Edit encrypted file or config if no path was given
     Edit {
         /// Path to encrypted file
-        file: Option<PathBuf>
+        file: Option<PathBuf>,
     },
     /// Reset config file to the base configuration
     Reset,
 }
+
+#[derive(Debug, Subcommand)]
+pub enum CredentialsEnum {
+    /// List users with credentials or the passwords of the given user
+    List {
+        /// User to list passwords for, will list users if not specified
+        user: Option<String>,
+        /// Show passwords in plain text
+        #[arg(short, long)]
+        show: bool,
+    },
+    /// Add a password to the specified user credentials
+    ///
+    /// Remote host keys are not verified, so every applicable password of the
+    /// user, including those of jump hosts, is sent to a remote until one is
+    /// accepted. Scope passwords with --host or --group, or with credential rules
+    /// in the config, to limit the remotes that can receive them.
+    Add {
+        /// User to add the password to, will ask for user if not specified
+        user: Option<String>,
+        /// Label to identify the password
+        #[arg(short, long, default_value = "")]
+        label: String,
+        /// Host glob pattern the password applies to, can be repeated
+        #[arg(short = 'H', long = "host", value_name = "PATTERN")]
+        hosts: Vec<String>,
+        /// Inventory group the password applies to, can be repeated
+        #[arg(short, long = "group", value_name = "GROUP")]
+        groups: Vec<String>,
+        /// Free-form notes for the password
+        #[arg(short, long, default_value = "")]
+        notes: String,
+        /// Position in the password list, appended at the end if not specified
+        #[arg(short, long)]
+        position: Option<usize>,
+    },
+    /// Remove a password from the specified user credentials
+    Remove {
+        /// User to remove the password from
+        user: String,
+        /// Label or index of the password to remove
+        password: String,
+    },
+    /// Edit credentials of the specified user as TOML
+    Edit {
+        /// User to modify credentials to, will ask for user if not specified
+        user: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand, Default)]
+pub enum CacheEnum {
+    /// List cached passwords with their age and expiration status
+    #[default]
+    List,
+    /// Decrypt and print cached passwords for the given remote
+    Show {
+        /// Remote to show cached passwords for
+        remote: String,
+        /// Only show the cached password for this login user
+        #[arg(short, long)]
+        login_name: Option<String>,
+        /// Only show the cached password for this port
+        #[arg(short, long)]
+        port: Option<u16>,
+    },
+    /// Remove cached passwords matching a host or user@host:port glob pattern
+    Rm {
+        /// Glob pattern, matched against the host unless it contains '@'
+        pattern: String,
+    },
+    /// Remove cached passwords older than the configured expire time
+    Prune,
+}
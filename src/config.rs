@@ -1,19 +1,69 @@
-use anyhow::Ok;
+use anyhow::{anyhow, Ok};
 use directories::{ProjectDirs, UserDirs};
 use log::debug;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::{fs, path::PathBuf};
 
+use crate::cache;
 use crate::fatal;
+use crate::ssh::Keepalive;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct Config {
     pub login_command: HashMap<String, String>,
     pub default_login_user: String,
     pub default_login_port: u16,
     pub ssh_options: Vec<String>,
     pub cached_remote_password_expire_time: String,
+    pub default_inventory: Option<String>,
+    pub ssh_config_files: Vec<String>,
+    pub proxy_jumps: HashMap<String, String>,
+    pub credential_rules: Vec<CredentialRule>,
+    pub control_master: bool,
+    pub control_persist: String,
+    pub forks: usize,
+    pub host_timeout: String,
+    pub record_sessions: Vec<RecordRule>,
+}
+
+/// Restricts the passwords tried for remotes matching any of the host globs,
+/// CIDR networks or inventory groups to the listed credential labels, in order
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CredentialRule {
+    pub hosts: Vec<String>,
+    pub networks: Vec<String>,
+    pub groups: Vec<String>,
+    pub user: Option<String>,
+    pub credentials: Vec<String>,
+}
+
+/// Records the interactive sessions of the listed users, any user if empty, in
+/// remotes matching any of the host globs or inventory groups, every remote if
+/// both are empty
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RecordRule {
+    pub hosts: Vec<String>,
+    pub groups: Vec<String>,
+    pub users: Vec<String>,
+}
+
+impl RecordRule {
+    pub fn matches(&self, user: &str, host: &str, groups: &[String]) -> bool {
+        if !self.users.is_empty() && !self.users.iter().any(|x| x == user) {
+            return false;
+        }
+        if self.hosts.is_empty() && self.groups.is_empty() {
+            return true;
+        }
+        self.hosts
+            .iter()
+            .any(|x| glob::Pattern::new(x).is_ok_and(|x| x.matches(host)))
+            || self.groups.iter().any(|x| groups.contains(x))
+    }
 }
 
 impl Config {
@@ -35,6 +85,35 @@ impl Config {
         Ok(config)
     }
 
+    /// Values of an OpenSSH option in `ssh_options`, e.g. `SendEnv=LANG LC_*`,
+    /// the name is case insensitive
+    pub fn ssh_option(&self, name: &str) -> Vec<String> {
+        self.ssh_options
+            .iter()
+            .filter_map(|x| {
+                let (key, value) = x.split_once(|x: char| x == '=' || x.is_whitespace())?;
+                key.eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_string())
+            })
+            .collect()
+    }
+
+    /// Keepalive from `ServerAliveInterval` and `ServerAliveCountMax` in
+    /// `ssh_options`, an interval of 0 disables it
+    pub fn keepalive(&self) -> anyhow::Result<Keepalive> {
+        let mut keepalive = Keepalive::default();
+        if let Some(interval) = self.ssh_option("ServerAliveInterval").first() {
+            let interval = cache::parse_duration(interval)?;
+            keepalive.interval = (!interval.is_zero()).then_some(interval);
+        }
+        if let Some(count) = self.ssh_option("ServerAliveCountMax").first() {
+            keepalive.count_max = count
+                .parse()
+                .map_err(|_| anyhow!("invalid ServerAliveCountMax {count:?}"))?;
+        }
+        Ok(keepalive)
+    }
+
     pub fn save(&self, path: &PathBuf) -> anyhow::Result<()> {
         let dir_path = std::path::Path::new(path).parent().unwrap();
         if !dir_path.exists() {
@@ -71,6 +150,18 @@ impl Default for Config {
             default_login_user: "root".to_string(),
             cached_remote_password_expire_time: "12h".to_string(),
             login_command,
+            default_inventory: None,
+            proxy_jumps: HashMap::new(),
+            ssh_config_files: vec![
+                "~/.ssh/config".to_string(),
+                "/etc/ssh/ssh_config".to_string(),
+            ],
+            credential_rules: Vec::new(),
+            control_master: false,
+            control_persist: "10m".to_string(),
+            forks: 10,
+            host_timeout: "0".to_string(),
+            record_sessions: Vec::new(),
         }
     }
 }
@@ -78,6 +169,7 @@ impl Default for Config {
 #[allow(unused)]
 #[derive(Debug)]
 pub struct ConfigDirs {
+    pub home: PathBuf,
     pub config: PathBuf,
     pub data: PathBuf,
     pub state: PathBuf,
@@ -92,6 +184,7 @@ impl ConfigDirs {
             .unwrap_or_else(|| fatal!("Was not able to set project dirs structure"));
         let user_dirs = UserDirs::new().unwrap();
         Self {
+            home: user_dirs.home_dir().to_owned(),
             data: proj_dirs.data_dir().to_owned(),
             config: proj_dirs.config_dir().to_owned(),
             state: proj_dirs
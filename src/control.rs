@@ -0,0 +1,372 @@
+use anyhow::{anyhow, bail, Ok};
+use async_trait::async_trait;
+use log::{debug, info, trace, warn};
+use russh::server::{self, Auth, Msg};
+use russh::{client, Channel, ChannelMsg};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::cache::{self, CacheKey};
+use crate::ssh::{self, Keepalive, Login, Session, SharedHandle};
+
+/// Connection handed to a master process, jump hosts first and the target last
+#[derive(Debug, Serialize, Deserialize)]
+struct MasterRequest {
+    persist: String,
+    keepalive: Keepalive,
+    hops: Vec<Login>,
+}
+
+/// Control socket of the master connection to a remote, named after a hash of
+/// the remote like OpenSSH `%C` so it fits in the unix socket path limit
+pub fn socket_path(state: &Path, key: &CacheKey) -> PathBuf {
+    let name = format!("{:x}", Sha256::digest(key.to_string()));
+    trace!("control socket for {key}: {name}");
+    state.join("control").join(name)
+}
+
+/// Log file of the master process, kept next to its socket
+fn log_path(socket: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.log", socket.display()))
+}
+
+/// Start a background master for the remote and wait until its socket is ready.
+/// Passwords are sent through the master stdin, never as arguments.
+pub fn spawn(socket: &Path, persist: &str, hops: &[Login]) -> anyhow::Result<()> {
+    let dir = socket.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    let log = File::create(log_path(socket))?;
+    debug!("starting master connection for {socket:?}");
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("controlmaster")
+        .arg(socket)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(log)
+        // keep the master out of the terminal process group so Ctrl-C does not reach it
+        .process_group(0)
+        .spawn()?;
+    let request = MasterRequest {
+        persist: persist.to_string(),
+        keepalive: ssh::keepalive(),
+        hops: hops.to_vec(),
+    };
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(toml::to_string(&request)?.as_bytes())?;
+    let mut status = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut status)?;
+    match status.trim() {
+        "ready" => Ok(()),
+        "" => bail!(anyhow!(
+            "master connection exited, see {:?}",
+            log_path(socket)
+        )),
+        error => bail!(anyhow!("{error}")),
+    }
+}
+
+/// Run the master connection: read the hops from stdin, connect and serve the
+/// control socket until no client used it for the persist time
+pub async fn master(socket: &Path) -> anyhow::Result<()> {
+    let result = connect_master(socket).await;
+    match result {
+        Result::Ok((session, listener, persist)) => {
+            println!("ready");
+            std::io::stdout().flush()?;
+            serve(session, listener, persist).await;
+            let _ = fs::remove_file(socket);
+            Ok(())
+        }
+        Err(error) => {
+            println!("{error}");
+            Err(error)
+        }
+    }
+}
+
+async fn connect_master(socket: &Path) -> anyhow::Result<(Session, UnixListener, Duration)> {
+    let mut data = String::new();
+    std::io::stdin().read_to_string(&mut data)?;
+    let request = toml::from_str::<MasterRequest>(&data)?;
+    let persist = cache::parse_duration(&request.persist)?;
+    ssh::set_keepalive(request.keepalive);
+    let mut session = None;
+    for hop in request.hops {
+        info!("connecting to {}@{}:{}", hop.user, hop.address, hop.port);
+        session = Some(
+            Session::connect(
+                hop.user,
+                hop.password,
+                &hop.identity_files,
+                (&hop.address, hop.port),
+                session,
+            )
+            .await?,
+        );
+    }
+    let session = session.ok_or_else(|| anyhow!("no remote to connect to"))?;
+    if socket.exists() {
+        if UnixStream::connect(socket).await.is_ok() {
+            bail!(anyhow!(
+                "a master connection is already running on {socket:?}"
+            ));
+        }
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    Ok((session, listener, persist))
+}
+
+async fn serve(mut session: Session, listener: UnixListener, persist: Duration) {
+    let upstream = session.handle();
+    let config = Arc::new(server::Config {
+        keys: vec![russh::keys::key::KeyPair::generate_ed25519().unwrap()],
+        auth_rejection_time: Duration::ZERO,
+        auth_rejection_time_initial: Some(Duration::ZERO),
+        ..Default::default()
+    });
+    let clients = Arc::new(AtomicUsize::new(0));
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Result::Ok((stream, _)) => stream,
+                    Err(error) => {
+                        warn!("unable to accept control connection: {error}");
+                        continue;
+                    }
+                };
+                let handler = Mux { upstream: upstream.clone() };
+                let config = config.clone();
+                let clients = clients.clone();
+                let last_active = last_active.clone();
+                clients.fetch_add(1, Ordering::SeqCst);
+                debug!("control client connected");
+                tokio::spawn(async move {
+                    match server::run_stream(config, stream, handler).await {
+                        Result::Ok(running) => {
+                            if let Err(error) = running.await {
+                                debug!("control client error: {error}");
+                            }
+                        }
+                        Err(error) => debug!("control client handshake failed: {error}"),
+                    }
+                    *last_active.lock().unwrap() = Instant::now();
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    debug!("control client disconnected");
+                });
+            }
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {
+                if upstream.read().await.is_closed() {
+                    info!("master connection closed by remote");
+                    break;
+                }
+                if clients.load(Ordering::SeqCst) == 0
+                    && last_active.lock().unwrap().elapsed() > persist
+                {
+                    info!("master connection idle, exiting");
+                    break;
+                }
+            }
+        }
+    }
+    if let Err(error) = session.close().await {
+        debug!("unable to close master connection: {error}");
+    }
+}
+
+/// SSH server side of the control socket, every channel opened by a client is
+/// relayed to a new channel of the upstream connection
+struct Mux {
+    upstream: SharedHandle,
+}
+
+#[async_trait]
+impl server::Handler for Mux {
+    type Error = anyhow::Error;
+
+    async fn auth_none(&mut self, _user: &str) -> Result<Auth, Self::Error> {
+        // access is restricted by the socket permissions
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut server::Session,
+    ) -> Result<bool, Self::Error> {
+        let upstream = match self.upstream.read().await.channel_open_session().await {
+            Result::Ok(upstream) => upstream,
+            Err(error) => {
+                warn!("unable to open upstream channel: {error}");
+                return Ok(false);
+            }
+        };
+        tokio::spawn(relay(channel, upstream, session.handle()));
+        Ok(true)
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host: &str,
+        port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        _session: &mut server::Session,
+    ) -> Result<bool, Self::Error> {
+        let upstream = match self
+            .upstream
+            .read()
+            .await
+            .channel_open_direct_tcpip(host, port, originator_address, originator_port)
+            .await
+        {
+            Result::Ok(upstream) => upstream,
+            Err(error) => {
+                warn!("unable to open upstream tunnel to {host}:{port}: {error}");
+                return Ok(false);
+            }
+        };
+        tokio::spawn(async move {
+            let mut upstream = upstream.into_stream();
+            let mut channel = channel.into_stream();
+            let _ = tokio::io::copy_bidirectional(&mut channel, &mut upstream).await;
+        });
+        Ok(true)
+    }
+}
+
+/// Forward requests and data between a client channel and its upstream channel
+async fn relay(
+    mut channel: Channel<Msg>,
+    mut upstream: Channel<client::Msg>,
+    handle: server::Handle,
+) -> anyhow::Result<()> {
+    let id = channel.id();
+    loop {
+        tokio::select! {
+            msg = channel.wait() => {
+                let Some(msg) = msg else {
+                    upstream.close().await?;
+                    break;
+                };
+                trace!("control client message: {msg:?}");
+                match msg {
+                    ChannelMsg::Data { data } => upstream.data(&data[..]).await?,
+                    ChannelMsg::Eof => upstream.eof().await?,
+                    ChannelMsg::Close => {
+                        upstream.close().await?;
+                        break;
+                    }
+                    ChannelMsg::RequestPty {
+                        want_reply,
+                        term,
+                        col_width,
+                        row_height,
+                        pix_width,
+                        pix_height,
+                        terminal_modes,
+                    } => {
+                        upstream
+                            .request_pty(
+                                want_reply,
+                                &term,
+                                col_width,
+                                row_height,
+                                pix_width,
+                                pix_height,
+                                &terminal_modes,
+                            )
+                            .await?
+                    }
+                    // replies are relayed back, so only those the client asked for are requested
+                    ChannelMsg::RequestShell { want_reply } => {
+                        upstream.request_shell(want_reply).await?
+                    }
+                    ChannelMsg::Exec {
+                        want_reply,
+                        command,
+                    } => upstream.exec(want_reply, command).await?,
+                    ChannelMsg::RequestSubsystem { want_reply, name } => {
+                        upstream.request_subsystem(want_reply, name).await?
+                    }
+                    ChannelMsg::SetEnv {
+                        want_reply,
+                        variable_name,
+                        variable_value,
+                    } => {
+                        upstream
+                            .set_env(want_reply, variable_name, variable_value)
+                            .await?
+                    }
+                    ChannelMsg::WindowChange {
+                        col_width,
+                        row_height,
+                        pix_width,
+                        pix_height,
+                    } => {
+                        upstream
+                            .window_change(col_width, row_height, pix_width, pix_height)
+                            .await?
+                    }
+                    ChannelMsg::Signal { signal } => upstream.signal(signal).await?,
+                    _ => {}
+                }
+            }
+            msg = upstream.wait() => {
+                let Some(msg) = msg else {
+                    let _ = handle.close(id).await;
+                    break;
+                };
+                let sent = match msg {
+                    ChannelMsg::Data { data } => handle.data(id, data).await.is_ok(),
+                    ChannelMsg::ExtendedData { data, ext } => {
+                        handle.extended_data(id, ext, data).await.is_ok()
+                    }
+                    ChannelMsg::Eof => handle.eof(id).await.is_ok(),
+                    ChannelMsg::ExitStatus { exit_status } => {
+                        handle.exit_status_request(id, exit_status).await.is_ok()
+                    }
+                    ChannelMsg::ExitSignal {
+                        signal_name,
+                        core_dumped,
+                        error_message,
+                        lang_tag,
+                    } => handle
+                        .exit_signal_request(id, signal_name, core_dumped, error_message, lang_tag)
+                        .await
+                        .is_ok(),
+                    ChannelMsg::Success => handle.channel_success(id).await.is_ok(),
+                    ChannelMsg::Failure => handle.channel_failure(id).await.is_ok(),
+                    ChannelMsg::Close => {
+                        let _ = handle.close(id).await;
+                        break;
+                    }
+                    _ => true,
+                };
+                if !sent {
+                    upstream.close().await?;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
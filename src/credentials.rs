@@ -0,0 +1,254 @@
+use anyhow::{anyhow, bail, Ok};
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::{fs, path::PathBuf};
+
+use crate::config::CredentialRule;
+use crate::encryption;
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Credentials {
+    #[serde(default, rename = "password")]
+    pub passwords: Vec<Credential>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Credential {
+    pub password: String,
+    #[serde(default)]
+    pub label: String,
+    /// Host glob patterns this password applies to, empty means any host
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Inventory groups this password applies to, empty means any group
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default = "Local::now")]
+    pub created: DateTime<Local>,
+    #[serde(default)]
+    pub notes: String,
+}
+
+/// Remote attributes used to select the credentials that apply to it
+#[derive(Debug, Default)]
+pub struct Scope {
+    pub host: String,
+    pub addrs: Vec<IpAddr>,
+    pub groups: Vec<String>,
+}
+
+impl Scope {
+    fn matches_host(&self, patterns: &[String]) -> bool {
+        patterns.iter().any(|pattern| {
+            glob::Pattern::new(pattern)
+                .map(|x| x.matches(&self.host))
+                .unwrap_or(false)
+        })
+    }
+
+    fn matches_network(&self, networks: &[String]) -> bool {
+        networks.iter().any(|network| {
+            self.addrs
+                .iter()
+                .any(|addr| network_contains(network, addr).unwrap_or(false))
+        })
+    }
+
+    fn matches_group(&self, groups: &[String]) -> bool {
+        groups.iter().any(|group| self.groups.contains(group))
+    }
+}
+
+impl CredentialRule {
+    pub fn matches(&self, user: &str, scope: &Scope) -> bool {
+        if self.user.as_ref().is_some_and(|x| x != user) {
+            return false;
+        }
+        if self.hosts.is_empty() && self.networks.is_empty() && self.groups.is_empty() {
+            return true;
+        }
+        scope.matches_host(&self.hosts)
+            || scope.matches_network(&self.networks)
+            || scope.matches_group(&self.groups)
+    }
+}
+
+/// Check if `addr` is inside a CIDR `network`, a bare address only matches itself
+fn network_contains(network: &str, addr: &IpAddr) -> anyhow::Result<bool> {
+    let (base, prefix) = network.split_once('/').unwrap_or((network, ""));
+    let base = base.parse::<IpAddr>()?;
+    let (base, addr, bits) = match (base, addr) {
+        (IpAddr::V4(base), IpAddr::V4(addr)) => {
+            (u32::from(base) as u128, u32::from(*addr) as u128, 32)
+        }
+        (IpAddr::V6(base), IpAddr::V6(addr)) => (u128::from(base), u128::from(*addr), 128),
+        _ => return Ok(false),
+    };
+    let prefix = if prefix.is_empty() {
+        bits
+    } else {
+        prefix.parse::<u32>()?
+    };
+    if prefix > bits {
+        bail!(anyhow!("invalid network prefix in {network:?}"));
+    }
+    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+    Ok((base << (128 - bits)) & mask == (addr << (128 - bits)) & mask)
+}
+
+/// Credentials of a user are stored in a file named after it, so it must be a
+/// plain file name
+pub fn check_user(user: &str) -> anyhow::Result<()> {
+    if user.is_empty() || user == "." || user == ".." || user.contains(['/', '\0']) {
+        bail!(anyhow!("invalid user name {user:?}"));
+    }
+    Ok(())
+}
+
+impl Credential {
+    /// Credentials restricted to hosts or groups only apply to matching remotes
+    pub fn applies_to(&self, scope: &Scope) -> bool {
+        if self.hosts.is_empty() && self.groups.is_empty() {
+            return true;
+        }
+        scope.matches_host(&self.hosts) || scope.matches_group(&self.groups)
+    }
+
+    pub fn new(password: impl Into<String>) -> Self {
+        Self {
+            password: password.into(),
+            label: String::new(),
+            hosts: Vec::new(),
+            groups: Vec::new(),
+            created: Local::now(),
+            notes: String::new(),
+        }
+    }
+}
+
+impl Credentials {
+    pub fn load(passphrase: &str, file: &PathBuf) -> anyhow::Result<Self> {
+        if !file.exists() {
+            debug!("no credentials file {file:?}, using empty credentials");
+            return Ok(Self::default());
+        }
+        let data = encryption::decrypt(passphrase, file)?;
+        Self::parse(&data)
+    }
+
+    fn parse(data: &str) -> anyhow::Result<Self> {
+        // a legacy password may happen to be valid TOML, only a password array is structured
+        let structured = toml::from_str::<toml::Table>(data)
+            .is_ok_and(|table| matches!(table.get("password"), Some(toml::Value::Array(_))));
+        if structured || data.contains("[[password]]") {
+            return Ok(toml::from_str::<Self>(data)?);
+        }
+        // credentials written before the structured format were a single password
+        debug!("legacy credentials format, using contents as password");
+        let mut credential = Credential::new(data.trim());
+        credential.label = "legacy".to_string();
+        Ok(Self {
+            passwords: vec![credential],
+        })
+    }
+
+    pub fn save(&self, passphrase: &str, file: &PathBuf) -> anyhow::Result<()> {
+        encryption::encrypt(passphrase, toml::to_string_pretty(self)?.as_bytes(), file)
+    }
+
+    /// Users with a credentials file in `dir`
+    pub fn users(dir: &PathBuf) -> anyhow::Result<Vec<String>> {
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut users = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect::<Vec<String>>();
+        users.sort();
+        Ok(users)
+    }
+
+    pub fn add(&mut self, credential: Credential, position: Option<usize>) {
+        match position {
+            Some(position) if position < self.passwords.len() => {
+                self.passwords.insert(position, credential)
+            }
+            _ => self.passwords.push(credential),
+        }
+    }
+
+    /// Append the password unless one with the same label and password exists,
+    /// returns whether it was added
+    pub fn merge(&mut self, credential: Credential) -> bool {
+        if self
+            .passwords
+            .iter()
+            .any(|x| x.password == credential.password && x.label == credential.label)
+        {
+            return false;
+        }
+        self.passwords.push(credential);
+        true
+    }
+
+    /// Remove a password by label or by its position in the list
+    pub fn remove(&mut self, key: &str) -> anyhow::Result<Credential> {
+        if let Some(index) = self.passwords.iter().position(|x| x.label == key) {
+            return Ok(self.passwords.remove(index));
+        }
+        match key.parse::<usize>() {
+            Result::Ok(index) if index < self.passwords.len() => Ok(self.passwords.remove(index)),
+            _ => bail!(anyhow!("no password with label or index '{key}'")),
+        }
+    }
+
+    /// Passwords to try for the remote, in order. Matching rules restrict and
+    /// order the passwords by label, otherwise the stored order is used.
+    pub fn candidates(
+        &self,
+        user: &str,
+        scope: &Scope,
+        rules: &[CredentialRule],
+    ) -> Vec<&Credential> {
+        let applicable = self
+            .passwords
+            .iter()
+            .filter(|x| x.applies_to(scope))
+            .collect::<Vec<&Credential>>();
+        let mut labels: Vec<&String> = Vec::new();
+        for rule in rules.iter().filter(|x| x.matches(user, scope)) {
+            debug!("credential rule matched: {rule:?}");
+            for label in &rule.credentials {
+                if !labels.contains(&label) {
+                    labels.push(label);
+                }
+            }
+        }
+        if labels.is_empty() {
+            return applicable;
+        }
+        labels
+            .into_iter()
+            .flat_map(|label| applicable.iter().filter(move |x| &x.label == label))
+            .copied()
+            .collect()
+    }
+
+    /// Open credentials in the user editor as TOML
+    pub fn edit(passphrase: &str, file: &PathBuf) -> anyhow::Result<()> {
+        let credentials = Self::load(passphrase, file)?;
+        let data = toml::to_string_pretty(&credentials)?;
+        let buffer = edit::edit(&data)?;
+        if data == buffer {
+            debug!("buffer unchanged");
+            warn!("{file:#?} unchanged");
+            return Ok(());
+        }
+        let edited = toml::from_str::<Self>(&buffer)?;
+        edited.save(passphrase, file)
+    }
+}
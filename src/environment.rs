@@ -0,0 +1,94 @@
+use log::trace;
+
+use crate::ssh::quote;
+
+/// Parse a `KEY=VALUE` environment variable
+pub fn parse(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("invalid variable {value:?}, expected KEY=VALUE"))?;
+    if key.is_empty()
+        || key.starts_with(|x: char| x.is_ascii_digit())
+        || !key.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+    {
+        return Err(format!("invalid variable name {key:?}"));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Local variables matching any of the `SendEnv` patterns, e.g. `LANG` or
+/// `LC_*`, followed by the `explicit` ones
+pub fn collect(patterns: &[String], explicit: &[(String, String)]) -> Vec<(String, String)> {
+    let patterns = patterns
+        .iter()
+        .filter_map(|x| glob::Pattern::new(x).ok())
+        .collect::<Vec<glob::Pattern>>();
+    let mut vars = std::env::vars()
+        .filter(|(key, _)| patterns.iter().any(|x| x.matches(key)))
+        .filter(|(key, _)| !explicit.iter().any(|(x, _)| x == key))
+        .collect::<Vec<(String, String)>>();
+    vars.sort();
+    vars.extend(explicit.iter().cloned());
+    trace!(
+        "environment to send: {:?}",
+        vars.iter().map(|x| &x.0).collect::<Vec<_>>()
+    );
+    vars
+}
+
+/// Shell statement exporting `vars`, prefixed to commands when the remote
+/// rejects environment requests
+pub fn exports(vars: &[(String, String)]) -> String {
+    if vars.is_empty() {
+        return String::new();
+    }
+    let vars = vars
+        .iter()
+        .map(|(key, value)| format!("{key}={}", quote(value)))
+        .collect::<Vec<String>>();
+    format!("export {}; ", vars.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_variable() {
+        assert_eq!(
+            parse("LC_ALL=C.UTF-8").unwrap(),
+            ("LC_ALL".to_string(), "C.UTF-8".to_string())
+        );
+        assert_eq!(
+            parse("_A1=x=y").unwrap(),
+            ("_A1".to_string(), "x=y".to_string())
+        );
+        assert_eq!(
+            parse("EMPTY=").unwrap(),
+            ("EMPTY".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn invalid_names() {
+        for value in ["=x", "1A=x", "A-B=x", "A B=x", "A.B=x", "É=x", "$(id)=x"] {
+            assert_eq!(
+                parse(value).unwrap_err(),
+                format!(
+                    "invalid variable name {:?}",
+                    value.split_once('=').unwrap().0
+                ),
+            );
+        }
+        assert!(parse("NOVALUE").unwrap_err().contains("expected KEY=VALUE"));
+    }
+
+    #[test]
+    fn export_statement() {
+        assert_eq!(exports(&[]), "");
+        assert_eq!(
+            exports(&[("A".to_string(), "it's".to_string())]),
+            "export A='it'\\''s'; "
+        );
+    }
+}
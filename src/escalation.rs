@@ -0,0 +1,169 @@
+use anyhow::{anyhow, bail, Ok};
+use clap::ValueEnum;
+use log::{debug, trace};
+use russh::ChannelMsg;
+use std::time::{SystemTime, UNIX_EPOCH};
+use strum::Display;
+
+use crate::environment;
+use crate::ssh::{quote, Session};
+
+/// How to run commands as another user
+#[derive(Debug, Clone, Copy, Default, ValueEnum, PartialEq, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum BecomeMethod {
+    /// `sudo -S`, answered with the password of the login user
+    #[default]
+    Sudo,
+    /// `su -`, answered with the password of the become user
+    Su,
+}
+
+/// User to run commands as and the password answering the escalation prompt
+#[derive(Debug, Clone)]
+pub struct Become {
+    pub method: BecomeMethod,
+    pub user: String,
+    pub password: String,
+}
+
+impl Become {
+    /// Wrap `command` so it runs as the become user, printing `marker` on its
+    /// own line once the escalation succeeded. `input` is the length of stdin.
+    fn command(&self, command: &str, marker: &str, input: usize) -> String {
+        match self.method {
+            BecomeMethod::Sudo => format!(
+                "sudo -S -p {} -u {} -- sh -c {}",
+                quote(&prompt(marker)),
+                quote(&self.user),
+                quote(&format!("echo {marker}; {command}"))
+            ),
+            // su reads the password from a terminal, which is then made raw so
+            // output is untouched and stdin is passed through a pipe ending after
+            // `input` bytes, as a terminal has no end of input
+            BecomeMethod::Su => {
+                let command = match input {
+                    0 => format!("{{ {command}\n}} </dev/null"),
+                    _ => format!("head -c {input} | {{ {command}\n}}"),
+                };
+                format!(
+                    "su - {} -c {}",
+                    quote(&self.user),
+                    quote(&format!(
+                        "stty raw -echo 2>/dev/null; echo {marker}; {command}"
+                    ))
+                )
+            }
+        }
+    }
+
+    fn is_prompt(&self, output: &[u8], marker: &str) -> bool {
+        let output = String::from_utf8_lossy(output);
+        match self.method {
+            BecomeMethod::Sudo => output.contains(&prompt(marker)),
+            BecomeMethod::Su => {
+                let output = output.trim_end().to_lowercase();
+                output.ends_with(':') && output.contains("password")
+            }
+        }
+    }
+}
+
+fn prompt(marker: &str) -> String {
+    format!("[{marker}] password:")
+}
+
+/// Run a command as the become user, answering the password prompt before
+/// sending `stdin`. Output is passed to `output` like `Session::exec`, with `su`
+/// running on a terminal everything is reported as stdout.
+pub async fn exec(
+    session: &Session,
+    escalation: &Become,
+    command: &str,
+    stdin: Option<&[u8]>,
+    mut output: impl FnMut(bool, &[u8]) -> std::io::Result<()>,
+) -> anyhow::Result<Option<u32>> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let marker = format!("asd-become-{:x}{nanos:x}", std::process::id());
+    let mut channel = session.handle().read().await.channel_open_session().await?;
+    if escalation.method == BecomeMethod::Su {
+        channel.request_pty(true, "dumb", 0, 0, 0, 0, &[]).await?;
+    }
+    // sudo and su reset the environment, so it is exported by the command
+    let command = format!("{}{command}", environment::exports(session.env()));
+    let command = escalation.command(&command, &marker, stdin.map_or(0, |x| x.len()));
+    trace!("become command: {command}");
+    channel.exec(true, command).await?;
+    // output until the marker is held back, it only has the escalation prompts
+    let mut pending: [Vec<u8>; 2] = [Vec::new(), Vec::new()];
+    let mut answered = false;
+    let mut escalated = false;
+    let mut code = None;
+    while let Some(msg) = channel.wait().await {
+        let (stderr, data) = match msg {
+            ChannelMsg::Data { ref data } => (false, data.to_vec()),
+            ChannelMsg::ExtendedData { ref data, ext: 1 } => (true, data.to_vec()),
+            ChannelMsg::ExitStatus { exit_status } => {
+                code = Some(exit_status);
+                continue;
+            }
+            ChannelMsg::ExitSignal { signal_name, .. } => {
+                bail!(anyhow!("command killed by signal {signal_name:?}"))
+            }
+            ChannelMsg::Failure => bail!(anyhow!("remote refused to run the command")),
+            ChannelMsg::Close => break,
+            _ => continue,
+        };
+        if escalated {
+            output(stderr, &data)?;
+            continue;
+        }
+        let buffer = &mut pending[stderr as usize];
+        buffer.extend_from_slice(&data);
+        let line = format!("{marker}\n");
+        if let Some(start) = find(buffer, line.as_bytes()) {
+            debug!("became {} with {}", escalation.user, escalation.method);
+            escalated = true;
+            let rest = buffer.split_off(start + line.len());
+            if !rest.is_empty() {
+                output(stderr, &rest)?;
+            }
+            if let Some(stdin) = stdin {
+                channel.data(stdin).await?;
+            }
+            channel.eof().await?;
+        } else if escalation.is_prompt(buffer, &marker) {
+            if answered {
+                bail!(anyhow!(
+                    "incorrect {} password to become {}",
+                    escalation.method,
+                    escalation.user
+                ));
+            }
+            debug!("answering {} password prompt", escalation.method);
+            channel
+                .data(format!("{}\n", escalation.password).as_bytes())
+                .await?;
+            buffer.clear();
+            answered = true;
+        }
+    }
+    if !escalated {
+        let message = pending
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<String>>()
+            .join(": ");
+        bail!(anyhow!(
+            "unable to become {} with {}: {message}",
+            escalation.user,
+            escalation.method
+        ));
+    }
+    Ok(code)
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|x| x == needle)
+}
@@ -0,0 +1,211 @@
+use anyhow::{anyhow, bail, Ok};
+
+use crate::forward::{self, Forward};
+
+/// Character starting an escape sequence
+const ESCAPE: u8 = b'~';
+
+pub const HELP: &str = "Supported escape sequences:
+ ~.   - terminate connection
+ ~C   - open a command line
+ ~^Z  - suspend asd
+ ~#   - list forwarded ports
+ ~?   - this message
+ ~~   - send the escape character by typing it twice
+(Note that escapes are only recognized immediately after newline.)
+";
+
+pub const COMMAND_HELP: &str = "Commands:
+      -L[bind_address:]port:host:hostport    Request local forward
+      -R[bind_address:]port:host:hostport    Request remote forward
+      -D[bind_address:]port                  Request dynamic forward
+";
+
+/// Escape sequences of an interactive session, as in OpenSSH
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escape {
+    /// `~.`
+    Disconnect,
+    /// `~^Z`
+    Suspend,
+    /// `~?`
+    Help,
+    /// `~#`
+    List,
+    /// `~C`
+    Command,
+}
+
+/// Typed input split into data for the remote and escape sequences
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    Data(Vec<u8>),
+    Escape(Escape),
+}
+
+/// Finds escape sequences in the typed input, which only start right after a
+/// newline. Keeps state between reads so sequences may be split across them.
+#[derive(Debug)]
+pub struct Escapes {
+    line_start: bool,
+    pending: bool,
+}
+
+impl Default for Escapes {
+    fn default() -> Self {
+        Self {
+            line_start: true,
+            pending: false,
+        }
+    }
+}
+
+impl Escapes {
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Input> {
+        let mut inputs = Vec::new();
+        let mut buffer = Vec::new();
+        for &byte in data {
+            if self.pending {
+                self.pending = false;
+                let escape = match byte {
+                    b'.' => Some(Escape::Disconnect),
+                    0x1a => Some(Escape::Suspend),
+                    b'?' => Some(Escape::Help),
+                    b'#' => Some(Escape::List),
+                    b'C' => Some(Escape::Command),
+                    _ => None,
+                };
+                if let Some(escape) = escape {
+                    // the line start is kept so sequences can follow each other
+                    if !buffer.is_empty() {
+                        inputs.push(Input::Data(std::mem::take(&mut buffer)));
+                    }
+                    inputs.push(Input::Escape(escape));
+                    continue;
+                }
+                // anything else sends the escape character as typed, once if doubled
+                if byte != ESCAPE {
+                    buffer.push(ESCAPE);
+                }
+            } else if self.line_start && byte == ESCAPE {
+                self.pending = true;
+                continue;
+            }
+            buffer.push(byte);
+            self.line_start = byte == b'\r' || byte == b'\n';
+        }
+        if !buffer.is_empty() {
+            inputs.push(Input::Data(buffer));
+        }
+        inputs
+    }
+}
+
+/// Line typed at the `~C` command line
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Forward(Forward),
+    Help,
+}
+
+/// Parse a line of the `~C` command line, `None` if there is nothing to do
+pub fn parse_command(line: &str) -> anyhow::Result<Option<Command>> {
+    let line = line.trim();
+    match line {
+        "" => return Ok(None),
+        "-h" | "?" => return Ok(Some(Command::Help)),
+        _ => {}
+    }
+    let (option, spec) = line.split_at(line.char_indices().nth(2).map_or(line.len(), |x| x.0));
+    let spec = spec.trim();
+    let forward = match option {
+        "-L" => forward::parse_local(spec)?,
+        "-R" => forward::parse_remote(spec)?,
+        "-D" => forward::parse_dynamic(spec)?,
+        _ => bail!(anyhow!("invalid command {line:?}")),
+    };
+    Ok(Some(Command::Forward(forward)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(data: &str) -> Input {
+        Input::Data(data.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn escape_at_start() {
+        let mut escapes = Escapes::default();
+        assert_eq!(escapes.feed(b"~?"), vec![Input::Escape(Escape::Help)]);
+        assert_eq!(escapes.feed(b"~#"), vec![Input::Escape(Escape::List)]);
+    }
+
+    #[test]
+    fn escape_split_across_reads() {
+        let mut escapes = Escapes::default();
+        assert_eq!(escapes.feed(b"ls\r~"), vec![data("ls\r")]);
+        assert_eq!(escapes.feed(b"."), vec![Input::Escape(Escape::Disconnect)]);
+    }
+
+    #[test]
+    fn escape_between_data() {
+        let mut escapes = Escapes::default();
+        assert_eq!(
+            escapes.feed(b"a\n~\x1aexit\n"),
+            vec![data("a\n"), Input::Escape(Escape::Suspend), data("exit\n")]
+        );
+    }
+
+    #[test]
+    fn doubled_escape_sends_one() {
+        let mut escapes = Escapes::default();
+        assert_eq!(escapes.feed(b"~~."), vec![data("~.")]);
+    }
+
+    #[test]
+    fn unknown_escape_sends_both() {
+        let mut escapes = Escapes::default();
+        assert_eq!(escapes.feed(b"~x"), vec![data("~x")]);
+    }
+
+    #[test]
+    fn escape_only_at_line_start() {
+        let mut escapes = Escapes::default();
+        assert_eq!(escapes.feed(b"a~."), vec![data("a~.")]);
+        assert_eq!(escapes.feed(b"~."), vec![data("~.")]);
+        assert_eq!(escapes.feed(b"\r"), vec![data("\r")]);
+        assert_eq!(escapes.feed(b"~."), vec![Input::Escape(Escape::Disconnect)]);
+    }
+
+    #[test]
+    fn command_line() {
+        assert!(parse_command("  ").unwrap().is_none());
+        assert_eq!(parse_command("?").unwrap(), Some(Command::Help));
+        assert_eq!(parse_command("-h").unwrap(), Some(Command::Help));
+        assert_eq!(
+            parse_command("-L 8080:localhost:80").unwrap(),
+            Some(Command::Forward(
+                forward::parse_local("8080:localhost:80").unwrap()
+            ))
+        );
+        assert_eq!(
+            parse_command("-R0:localhost:22").unwrap(),
+            Some(Command::Forward(
+                forward::parse_remote("0:localhost:22").unwrap()
+            ))
+        );
+        assert_eq!(
+            parse_command("-D 1080").unwrap(),
+            Some(Command::Forward(forward::parse_dynamic("1080").unwrap()))
+        );
+    }
+
+    #[test]
+    fn invalid_command_line() {
+        assert!(parse_command("-X 1").is_err());
+        assert!(parse_command("-L").is_err());
+        assert!(parse_command("-Lé").is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use anyhow::{anyhow, bail, Ok};
+use log::{debug, warn};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::ssh::SharedHandle;
+
+/// Port forwarding requested with `-L`, `-R` or `-D`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Forward {
+    /// Listen locally and connect to `host:host_port` from the remote
+    Local {
+        bind: String,
+        port: u16,
+        host: String,
+        host_port: u16,
+    },
+    /// Listen on the remote and connect to `host:host_port` from this machine
+    Remote {
+        bind: String,
+        port: u16,
+        host: String,
+        host_port: u16,
+    },
+    /// Listen locally as a SOCKS5 proxy connecting from the remote
+    Dynamic { bind: String, port: u16 },
+}
+
+impl std::fmt::Display for Forward {
+    /// The forward as given on the command line
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Forward::Local {
+                bind,
+                port,
+                host,
+                host_port,
+            } => write!(
+                f,
+                "-L {}:{port}:{}:{host_port}",
+                bracket(bind),
+                bracket(host)
+            ),
+            Forward::Remote {
+                bind,
+                port,
+                host,
+                host_port,
+            } => write!(
+                f,
+                "-R {}:{port}:{}:{host_port}",
+                bracket(bind),
+                bracket(host)
+            ),
+            Forward::Dynamic { bind, port } => write!(f, "-D {}:{port}", bracket(bind)),
+        }
+    }
+}
+
+/// Enclose IPv6 addresses in brackets
+fn bracket(address: &str) -> String {
+    if address.contains(':') {
+        format!("[{address}]")
+    } else {
+        address.to_string()
+    }
+}
+
+/// Parse `[bind:]port:host:hostport` for `-L`
+pub fn parse_local(value: &str) -> anyhow::Result<Forward> {
+    let (bind, port, host, host_port) = parse_tunnel(value)?;
+    Ok(Forward::Local {
+        bind,
+        port,
+        host,
+        host_port,
+    })
+}
+
+/// Parse `[bind:]port:host:hostport` for `-R`
+pub fn parse_remote(value: &str) -> anyhow::Result<Forward> {
+    let (bind, port, host, host_port) = parse_tunnel(value)?;
+    Ok(Forward::Remote {
+        bind,
+        port,
+        host,
+        host_port,
+    })
+}
+
+/// Parse `[bind:]port` for `-D`
+pub fn parse_dynamic(value: &str) -> anyhow::Result<Forward> {
+    let parts = split_address(value)?;
+    let (bind, port) = match parts.as_slice() {
+        [port] => ("localhost".to_string(), port),
+        [bind, port] => (bind.clone(), port),
+        _ => bail!(anyhow!(
+            "invalid dynamic forward {value:?}, expected [bind:]port"
+        )),
+    };
+    Ok(Forward::Dynamic {
+        bind,
+        port: parse_port(port)?,
+    })
+}
+
+fn parse_tunnel(value: &str) -> anyhow::Result<(String, u16, String, u16)> {
+    let parts = split_address(value)?;
+    let (bind, port, host, host_port) = match parts.as_slice() {
+        [port, host, host_port] => ("localhost".to_string(), port, host, host_port),
+        [bind, port, host, host_port] => (bind.clone(), port, host, host_port),
+        _ => bail!(anyhow!(
+            "invalid forward {value:?}, expected [bind:]port:host:hostport"
+        )),
+    };
+    Ok((
+        bind,
+        parse_port(port)?,
+        host.clone(),
+        parse_port(host_port)?,
+    ))
+}
+
+fn parse_port(value: &str) -> anyhow::Result<u16> {
+    value.parse().map_err(|_| anyhow!("invalid port {value:?}"))
+}
+
+/// Split on `:`, IPv6 addresses must be enclosed in brackets
+fn split_address(value: &str) -> anyhow::Result<Vec<String>> {
+    let mut parts = Vec::new();
+    let mut rest = value;
+    loop {
+        let part = if let Some(address) = rest.strip_prefix('[') {
+            let (address, tail) = address
+                .split_once(']')
+                .ok_or_else(|| anyhow!("unclosed bracket in {value:?}"))?;
+            rest = tail;
+            address
+        } else {
+            let end = rest.find(':').unwrap_or(rest.len());
+            let (part, tail) = rest.split_at(end);
+            rest = tail;
+            part
+        };
+        parts.push(part.to_string());
+        match rest.strip_prefix(':') {
+            Some(tail) => rest = tail,
+            None if rest.is_empty() => return Ok(parts),
+            None => bail!(anyhow!("invalid address {value:?}")),
+        }
+    }
+}
+
+/// Accept local connections and tunnel them to `host:port` through the remote
+pub async fn listen_local(session: SharedHandle, listener: TcpListener, host: String, port: u16) {
+    loop {
+        let (stream, origin) = match listener.accept().await {
+            Result::Ok(client) => client,
+            Err(error) => {
+                warn!("unable to accept forwarded connection: {error}");
+                continue;
+            }
+        };
+        debug!("forwarding connection from {origin} to {host}:{port}");
+        let session = session.clone();
+        let host = host.clone();
+        tokio::spawn(async move {
+            let channel = session
+                .read()
+                .await
+                .channel_open_direct_tcpip(
+                    &host,
+                    port as u32,
+                    origin.ip().to_string(),
+                    origin.port() as u32,
+                )
+                .await;
+            match channel {
+                Result::Ok(channel) => tunnel(stream, channel.into_stream()).await,
+                Err(error) => warn!("unable to open channel to {host}:{port}: {error}"),
+            }
+        });
+    }
+}
+
+/// Accept local SOCKS5 clients and tunnel them to the requested destination
+pub async fn listen_dynamic(session: SharedHandle, listener: TcpListener) {
+    loop {
+        let (stream, origin) = match listener.accept().await {
+            Result::Ok(client) => client,
+            Err(error) => {
+                warn!("unable to accept socks connection: {error}");
+                continue;
+            }
+        };
+        let session = session.clone();
+        tokio::spawn(async move {
+            if let Err(error) = socks(session, stream, origin).await {
+                warn!("socks connection from {origin} failed: {error}");
+            }
+        });
+    }
+}
+
+/// Handle a SOCKS5 `CONNECT` request without authentication
+async fn socks(
+    session: SharedHandle,
+    mut stream: TcpStream,
+    origin: std::net::SocketAddr,
+) -> anyhow::Result<()> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != 5 {
+        bail!(anyhow!("unsupported socks version {}", header[0]));
+    }
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&0) {
+        stream.write_all(&[5, 0xff]).await?;
+        bail!(anyhow!("client requires socks authentication"));
+    }
+    stream.write_all(&[5, 0]).await?;
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut address = [0; 4];
+            stream.read_exact(&mut address).await?;
+            Ipv4Addr::from(address).to_string()
+        }
+        3 => {
+            let mut address = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut address).await?;
+            String::from_utf8(address)?
+        }
+        4 => {
+            let mut address = [0; 16];
+            stream.read_exact(&mut address).await?;
+            Ipv6Addr::from(address).to_string()
+        }
+        kind => {
+            stream.write_all(&[5, 8, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            bail!(anyhow!("unsupported socks address type {kind}"));
+        }
+    };
+    let port = stream.read_u16().await?;
+    if request[1] != 1 {
+        stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        bail!(anyhow!("unsupported socks command {}", request[1]));
+    }
+    debug!("socks connection from {origin} to {host}:{port}");
+    let channel = match session
+        .read()
+        .await
+        .channel_open_direct_tcpip(
+            &host,
+            port as u32,
+            origin.ip().to_string(),
+            origin.port() as u32,
+        )
+        .await
+    {
+        Result::Ok(channel) => channel,
+        Err(error) => {
+            stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            bail!(anyhow!("unable to open channel to {host}:{port}: {error}"));
+        }
+    };
+    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    tunnel(stream, channel.into_stream()).await;
+    Ok(())
+}
+
+/// Copy data both ways until either side closes
+pub async fn tunnel<S>(mut stream: TcpStream, mut channel: S)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    match tokio::io::copy_bidirectional(&mut stream, &mut channel).await {
+        Result::Ok((sent, received)) => {
+            debug!("tunnel closed, {sent} bytes sent, {received} bytes received")
+        }
+        Err(error) => debug!("tunnel closed: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_forwards() {
+        assert_eq!(
+            parse_local("8080:web:80").unwrap(),
+            Forward::Local {
+                bind: "localhost".to_string(),
+                port: 8080,
+                host: "web".to_string(),
+                host_port: 80,
+            }
+        );
+        assert_eq!(
+            parse_local("[::1]:8080:[fd00::2]:80").unwrap(),
+            Forward::Local {
+                bind: "::1".to_string(),
+                port: 8080,
+                host: "fd00::2".to_string(),
+                host_port: 80,
+            }
+        );
+    }
+
+    #[test]
+    fn remote_forwards() {
+        assert_eq!(
+            parse_remote("0.0.0.0:9000:localhost:3000").unwrap(),
+            Forward::Remote {
+                bind: "0.0.0.0".to_string(),
+                port: 9000,
+                host: "localhost".to_string(),
+                host_port: 3000,
+            }
+        );
+    }
+
+    #[test]
+    fn dynamic_forwards() {
+        assert_eq!(
+            parse_dynamic("1080").unwrap(),
+            Forward::Dynamic {
+                bind: "localhost".to_string(),
+                port: 1080,
+            }
+        );
+        assert_eq!(
+            parse_dynamic("[::]:1080").unwrap(),
+            Forward::Dynamic {
+                bind: "::".to_string(),
+                port: 1080,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_forwards() {
+        assert_eq!(
+            parse_local("8080:web").unwrap_err().to_string(),
+            "invalid forward \"8080:web\", expected [bind:]port:host:hostport"
+        );
+        assert_eq!(
+            parse_remote("9000:web:http").unwrap_err().to_string(),
+            "invalid port \"http\""
+        );
+        assert_eq!(
+            parse_dynamic("[::1:1080").unwrap_err().to_string(),
+            "unclosed bracket in \"[::1:1080\""
+        );
+        assert_eq!(
+            parse_dynamic("a:b:1080").unwrap_err().to_string(),
+            "invalid dynamic forward \"a:b:1080\", expected [bind:]port"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{anyhow, bail, Ok};
+use clap::ValueEnum;
+use log::{debug, trace, warn};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{collections::HashMap, fs};
+
+use crate::credentials::{self, Credential};
+
+/// Password manager export formats supported by `asd config import`
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ImportFormat {
+    /// password-store directory, entries are decrypted with the user gpg key
+    Pass,
+    /// KeePass 2 XML export
+    KeepassXml,
+    /// Bitwarden (or compatible) CSV export
+    Csv,
+}
+
+/// Password read from a password manager, `user` is empty if the source had none
+#[derive(Debug)]
+pub struct ImportEntry {
+    pub user: String,
+    pub credential: Credential,
+}
+
+impl ImportEntry {
+    fn new(
+        user: &str,
+        label: &str,
+        password: &str,
+        url: &str,
+        notes: &str,
+    ) -> anyhow::Result<Self> {
+        let user = user.trim();
+        if !user.is_empty() {
+            credentials::check_user(user).map_err(|error| anyhow!("{error} in entry {label:?}"))?;
+        }
+        let mut credential = Credential::new(password);
+        credential.label = label.to_string();
+        credential.notes = notes.trim().to_string();
+        if let Some(host) = host_from_url(url) {
+            credential.hosts.push(host);
+        }
+        Ok(Self {
+            user: user.to_string(),
+            credential,
+        })
+    }
+}
+
+pub fn read(format: ImportFormat, source: &Path) -> anyhow::Result<Vec<ImportEntry>> {
+    let entries = match format {
+        ImportFormat::Pass => read_pass(source)?,
+        ImportFormat::KeepassXml => read_keepass_xml(&fs::read_to_string(source)?)?,
+        ImportFormat::Csv => read_csv(source)?,
+    };
+    trace!("read {} entries from {source:?}", entries.len());
+    Ok(entries)
+}
+
+fn read_pass(dir: &Path) -> anyhow::Result<Vec<ImportEntry>> {
+    if !dir.is_dir() {
+        bail!(anyhow!("password store {dir:?} is not a directory"));
+    }
+    let pattern = dir.join("**").join("*.gpg");
+    let mut files = glob::glob(&pattern.to_string_lossy())?
+        .filter_map(|x| x.ok())
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+    let mut entries = Vec::new();
+    for file in files {
+        let name = file
+            .strip_prefix(dir)?
+            .with_extension("")
+            .to_string_lossy()
+            .to_string();
+        debug!("decrypting password store entry: {name}");
+        let output = Command::new("gpg")
+            .arg("--quiet")
+            .arg("--batch")
+            .arg("--decrypt")
+            .arg(&file)
+            .output()?;
+        if !output.status.success() {
+            warn!("unable to decrypt {name}, skipping");
+            continue;
+        }
+        let data = String::from_utf8(output.stdout)?;
+        let mut lines = data.lines();
+        let password = lines.next().unwrap_or_default();
+        let mut fields: HashMap<String, &str> = HashMap::new();
+        let mut notes = String::new();
+        for line in lines {
+            match line.split_once(':') {
+                Some((key, value)) if !key.contains(' ') => {
+                    fields.insert(key.trim().to_lowercase(), value.trim());
+                }
+                _ => notes.push_str(&format!("{line}\n")),
+            }
+        }
+        let user = ["user", "username", "login"]
+            .iter()
+            .find_map(|x| fields.get(*x))
+            .unwrap_or(&"");
+        let url = ["url", "host"]
+            .iter()
+            .find_map(|x| fields.get(*x))
+            .unwrap_or(&"");
+        entries.push(ImportEntry::new(user, &name, password, url, &notes)?);
+    }
+    Ok(entries)
+}
+
+fn read_keepass_xml(data: &str) -> anyhow::Result<Vec<ImportEntry>> {
+    let document = roxmltree::Document::parse(data)?;
+    let mut entries = Vec::new();
+    for entry in document.descendants().filter(|x| x.has_tag_name("Entry")) {
+        // entries keep their previous versions inside a History element
+        if entry.ancestors().any(|x| x.has_tag_name("History")) {
+            continue;
+        }
+        let mut fields: HashMap<&str, &str> = HashMap::new();
+        for string in entry.children().filter(|x| x.has_tag_name("String")) {
+            let child = |name| {
+                string
+                    .children()
+                    .find(|x| x.has_tag_name(name))
+                    .and_then(|x| x.text())
+            };
+            if let Some(key) = child("Key") {
+                fields.insert(key, child("Value").unwrap_or_default());
+            }
+        }
+        let password = fields.get("Password").copied().unwrap_or_default();
+        if password.is_empty() {
+            continue;
+        }
+        let group = entry
+            .ancestors()
+            .filter(|x| x.has_tag_name("Group"))
+            .filter_map(|x| x.children().find(|x| x.has_tag_name("Name")))
+            .filter_map(|x| x.text())
+            .collect::<Vec<&str>>();
+        let label = group
+            .into_iter()
+            .rev()
+            .skip(1)
+            .chain(fields.get("Title").copied())
+            .collect::<Vec<&str>>()
+            .join("/");
+        entries.push(ImportEntry::new(
+            fields.get("UserName").copied().unwrap_or_default(),
+            &label,
+            password,
+            fields.get("URL").copied().unwrap_or_default(),
+            fields.get("Notes").copied().unwrap_or_default(),
+        )?);
+    }
+    Ok(entries)
+}
+
+fn read_csv(file: &Path) -> anyhow::Result<Vec<ImportEntry>> {
+    let mut reader = csv::Reader::from_path(file)?;
+    let headers = reader.headers()?.clone();
+    let column = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| headers.iter().position(|x| x.eq_ignore_ascii_case(name)))
+    };
+    let password = column(&["login_password", "password"])
+        .ok_or_else(|| anyhow!("no password column in {file:?}"))?;
+    let user = column(&["login_username", "username", "user"]);
+    let url = column(&["login_uri", "url", "uri"]);
+    let label = column(&["name", "title"]);
+    let notes = column(&["notes"]);
+    let mut entries = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let field = |index: Option<usize>| index.and_then(|x| record.get(x)).unwrap_or_default();
+        if field(Some(password)).is_empty() {
+            continue;
+        }
+        entries.push(ImportEntry::new(
+            field(user),
+            field(label),
+            field(Some(password)),
+            field(url),
+            field(notes),
+        )?);
+    }
+    Ok(entries)
+}
+
+/// Host name from an URL such as `ssh://root@web01:22/`, `None` if empty
+fn host_from_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let url = url.split_once("://").map(|x| x.1).unwrap_or(url);
+    let authority = url.split('/').next().unwrap_or_default();
+    let host = authority.rsplit_once('@').map(|x| x.1).unwrap_or(authority);
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|x| x.is_ascii_digit()) => host,
+        _ => host,
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_string())
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, bail, Ok};
+use log::{debug, trace};
+use std::collections::BTreeMap;
+use std::{fs, path::Path};
+
+/// Ansible style inventory, loaded from an INI file or a comma separated host list
+#[derive(Debug, Default)]
+pub struct Inventory {
+    hosts: Vec<String>,
+    groups: BTreeMap<String, Group>,
+    /// Variables set in the host line, e.g. `web1 http_port=8080`
+    host_vars: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    hosts: Vec<String>,
+    children: Vec<String>,
+    vars: BTreeMap<String, String>,
+}
+
+enum Section {
+    Hosts(String),
+    Children(String),
+    Vars(String),
+}
+
+impl Inventory {
+    pub fn load(spec: &str) -> anyhow::Result<Self> {
+        let path = Path::new(spec);
+        if path.is_file() {
+            debug!("loading inventory file: {path:?}");
+            Self::parse(&fs::read_to_string(path)?)
+        } else if spec.contains(',') || !spec.contains('/') {
+            debug!("using inventory as comma separated host list");
+            let mut inventory = Self::default();
+            for host in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+                for host in expand_range(host)? {
+                    inventory.add_host("ungrouped", &host);
+                }
+            }
+            Ok(inventory)
+        } else {
+            bail!(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("inventory {spec:?} not found")
+            ))
+        }
+    }
+
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let mut inventory = Self::default();
+        let mut section = Section::Hosts("ungrouped".to_string());
+        for (number, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                section = match header.split_once(':') {
+                    Some((name, "children")) => Section::Children(name.to_string()),
+                    Some((name, "vars")) => Section::Vars(name.to_string()),
+                    Some(_) => bail!(anyhow!("invalid inventory section at line {}", number + 1)),
+                    None => Section::Hosts(header.to_string()),
+                };
+                let (Section::Hosts(name) | Section::Children(name) | Section::Vars(name)) =
+                    &section;
+                inventory.groups.entry(name.clone()).or_default();
+                continue;
+            }
+            match &section {
+                Section::Hosts(group) => {
+                    let mut words = split_words(line).into_iter();
+                    let Some(pattern) = words.next() else {
+                        bail!(anyhow!("missing host at line {}", number + 1));
+                    };
+                    let vars = words
+                        .map(|x| {
+                            x.split_once('=')
+                                .map(|(key, value)| (key.to_string(), value.to_string()))
+                                .ok_or_else(|| anyhow!("invalid variable at line {}", number + 1))
+                        })
+                        .collect::<anyhow::Result<Vec<(String, String)>>>()?;
+                    for host in expand_range(&pattern)? {
+                        inventory.add_host(group, &host);
+                        inventory
+                            .host_vars
+                            .entry(host)
+                            .or_default()
+                            .extend(vars.iter().cloned());
+                    }
+                }
+                Section::Children(group) => {
+                    inventory
+                        .groups
+                        .entry(group.clone())
+                        .or_default()
+                        .children
+                        .push(line.to_string());
+                    inventory.groups.entry(line.to_string()).or_default();
+                }
+                Section::Vars(group) => {
+                    let (key, value) = line
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("invalid variable at line {}", number + 1))?;
+                    inventory
+                        .groups
+                        .entry(group.clone())
+                        .or_default()
+                        .vars
+                        .insert(key.trim().to_string(), unquote(value.trim()));
+                }
+            }
+        }
+        trace!("parsed inventory: {inventory:?}");
+        Ok(inventory)
+    }
+
+    fn add_host(&mut self, group: &str, host: &str) {
+        if !self.hosts.iter().any(|x| x == host) {
+            self.hosts.push(host.to_string());
+        }
+        let group = self.groups.entry(group.to_string()).or_default();
+        if !group.hosts.iter().any(|x| x == host) {
+            group.hosts.push(host.to_string());
+        }
+    }
+
+    /// Every host in the order they were first listed
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    /// Every group the host belongs to, directly or through group children
+    pub fn groups_of(&self, host: &str) -> Vec<String> {
+        let mut groups = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.hosts.iter().any(|x| x == host))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<String>>();
+        let mut index = 0;
+        while index < groups.len() {
+            for (name, group) in &self.groups {
+                if group.children.contains(&groups[index]) && !groups.contains(name) {
+                    groups.push(name.clone());
+                }
+            }
+            index += 1;
+        }
+        if self.hosts.iter().any(|x| x == host) {
+            groups.push("all".to_string());
+        }
+        groups
+    }
+
+    /// Variables of a host, group variables are overridden by those of child
+    /// groups and every group variable by the host line ones
+    pub fn vars(&self, host: &str) -> BTreeMap<String, String> {
+        let mut vars = BTreeMap::new();
+        for name in self.groups_of(host).iter().rev() {
+            if let Some(group) = self.groups.get(name) {
+                vars.extend(group.vars.clone());
+            }
+        }
+        if let Some(host_vars) = self.host_vars.get(host) {
+            vars.extend(host_vars.clone());
+        }
+        vars
+    }
+}
+
+/// Split a host line by whitespace, keeping quoted values together and unquoted
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for char in line.chars() {
+        match (quote, char) {
+            (None, '"' | '\'') => quote = Some(char),
+            (Some(open), _) if open == char => quote = None,
+            (None, _) if char.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            _ => word.push(char),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn unquote(value: &str) -> String {
+    value
+        .strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// Expand ansible host ranges such as `web[01:10].example.com`
+fn expand_range(pattern: &str) -> anyhow::Result<Vec<String>> {
+    let Some((prefix, rest)) = pattern.split_once('[') else {
+        return Ok(vec![pattern.to_string()]);
+    };
+    let (range, suffix) = rest
+        .split_once(']')
+        .ok_or_else(|| anyhow!("unclosed host range in {pattern:?}"))?;
+    let (start, end) = range
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid host range in {pattern:?}"))?;
+    let width = if start.starts_with('0') {
+        start.len()
+    } else {
+        0
+    };
+    let mut hosts = Vec::new();
+    for number in start.parse::<u64>()?..=end.parse::<u64>()? {
+        for suffix in expand_range(suffix)? {
+            hosts.push(format!("{prefix}{number:0width$}{suffix}"));
+        }
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_plain_words() {
+        assert_eq!(
+            split_words("  web01  ansible_port=2222\tenv=prod "),
+            vec!["web01", "ansible_port=2222", "env=prod"]
+        );
+        assert!(split_words("   ").is_empty());
+    }
+
+    #[test]
+    fn split_quoted_vars() {
+        assert_eq!(
+            split_words(r#"web01 motd="hello world" owner='ops team' empty="""#),
+            vec!["web01", "motd=hello world", "owner=ops team", "empty="]
+        );
+        assert_eq!(
+            split_words(r#"web01 quote="it's" other='say "hi"'"#),
+            vec!["web01", "quote=it's", "other=say \"hi\""]
+        );
+    }
+
+    #[test]
+    fn missing_host() {
+        let error = Inventory::parse("[web]\nweb01\n''\n").unwrap_err();
+        assert_eq!(error.to_string(), "missing host at line 3");
+    }
+}
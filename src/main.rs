@@ -1,24 +1,78 @@
+mod audit;
+mod backup;
+mod cache;
 mod cli;
 mod config;
+mod control;
+mod credentials;
 mod encryption;
+mod environment;
+mod escalation;
+mod escape;
+mod forward;
+mod import;
+mod inventory;
 mod macros;
+mod output;
+mod progress;
+mod recording;
+mod report;
+mod runner;
+mod script;
 mod ssh;
+mod ssh_config;
+mod template;
+mod transfer;
 
-use crate::ssh::Session;
-use anyhow::bail;
-use cli::{CommandEnum, ConfigEnum, ConnectionArgs, Parser};
+use crate::ssh::{ConnectError, Login, PasswordSource, Session};
+use anyhow::{anyhow, bail};
+use backup::Backup;
+use cache::{CacheKey, CachedPassword, PasswordCache};
+use cli::{
+    BecomeArgs, CacheEnum, CommandEnum, ConfigEnum, ConnectionArgs, CredentialsEnum, ExecuteArgs,
+    FileArgs, Parser, PutArgs, RunnerArgs,
+};
 use config::{Config, ConfigDirs};
-use glob::glob;
+use credentials::{Credential, Credentials, Scope};
+use escalation::{Become, BecomeMethod};
+use import::ImportEntry;
+use inventory::Inventory;
 use log::{debug, trace, warn};
+use output::{Mode, Printer};
+use progress::Progress;
+use recording::Recording;
+use report::{ErrorClass, Format, Report, Target};
+use runner::{HostResult, Runner};
 use scanpw::scanpw;
+use script::Script;
+use ssh_config::{HostConfig, SshConfig};
 use std::{
+    collections::{BTreeMap, HashMap},
     env::args,
     fs,
+    future::Future,
     io::{self, Write},
+    net::{IpAddr, ToSocketAddrs},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 use strum::IntoEnumIterator;
-use termion::raw::IntoRawMode;
+use tokio::sync::Mutex;
+
+/// Hosts run in parallel take turns on the terminal
+static PROMPT: Mutex<()> = Mutex::const_new(());
+
+/// Ask for a password on the terminal without blocking the async workers
+async fn prompt_password(prompt: String) -> anyhow::Result<String> {
+    let _prompt = PROMPT.lock().await;
+    Ok(tokio::task::spawn_blocking(move || {
+        let password = scanpw!("{prompt}");
+        println!();
+        password
+    })
+    .await?)
+}
 
 trait UnwrapExit<T> {
     fn unwrap_or_exit(self) -> T;
@@ -62,10 +116,10 @@ async fn main() -> anyhow::Result<()> {
     let config_path = dirs.config.join("config.toml");
 
     match cli.command {
-        CommandEnum::Ssh(args) => {
+        CommandEnum::Ssh(mut args) => {
             ssh(
                 &encryption::get_passphrase(&passfile).unwrap_or_exit(),
-                &args,
+                &mut args,
                 &Config::new(&config_path),
                 &dirs,
             )
@@ -73,10 +127,42 @@ async fn main() -> anyhow::Result<()> {
             .unwrap_or_exit();
         }
         CommandEnum::Sftp(_args) => {}
-        CommandEnum::Put(_args) => {}
-        CommandEnum::Get(_args) => {}
-        CommandEnum::Exec(_args) => {}
+        CommandEnum::Put(args) => {
+            let context =
+                Context::new(&passfile, Config::new(&config_path), dirs, "put").unwrap_or_exit();
+            put(args, Arc::new(context)).await.unwrap_or_exit();
+        }
+        CommandEnum::Get(args) => {
+            let context =
+                Context::new(&passfile, Config::new(&config_path), dirs, "get").unwrap_or_exit();
+            get(args, Arc::new(context)).await.unwrap_or_exit();
+        }
+        CommandEnum::Exec(args) => {
+            let mut context =
+                Context::new(&passfile, Config::new(&config_path), dirs, "exec").unwrap_or_exit();
+            context.commands = executed(&args);
+            exec(args, Arc::new(context)).await.unwrap_or_exit();
+        }
         CommandEnum::Book(_args) => {}
+        CommandEnum::Cache(command) => {
+            cache_command(&passfile, command, &Config::new(&config_path), &dirs).unwrap_or_exit();
+        }
+        CommandEnum::Replay {
+            file,
+            speed,
+            idle_limit,
+        } => {
+            let idle_limit = idle_limit
+                .map(Duration::try_from_secs_f64)
+                .transpose()
+                .unwrap_or_exit();
+            recording::replay(&file, speed, idle_limit)
+                .await
+                .unwrap_or_exit();
+        }
+        CommandEnum::ControlMaster { socket } => {
+            control::master(&socket).await.unwrap_or_exit();
+        }
         CommandEnum::Config(command) => match command {
             ConfigEnum::Init => {
                 let mut config = Config::new(&config_path);
@@ -131,13 +217,59 @@ async fn main() -> anyhow::Result<()> {
             ConfigEnum::Reset => {
                 Config::reset(&dirs.config.join("config.toml")).unwrap_or_exit();
             }
+            ConfigEnum::Import {
+                from,
+                source,
+                login_name,
+                dry_run,
+            } => {
+                let entries = import::read(from, &source).unwrap_or_exit();
+                let user = login_name.unwrap_or(Config::new(&config_path).default_login_user);
+                if dry_run {
+                    preview_import(&entries, &user);
+                } else {
+                    import_credentials(
+                        &encryption::get_passphrase(&passfile).unwrap_or_exit(),
+                        entries,
+                        &user,
+                        &dirs.data.join("credentials"),
+                    )
+                    .unwrap_or_exit();
+                }
+            }
+            ConfigEnum::Export { file, cache } => {
+                let passphrase = encryption::get_passphrase(&passfile).unwrap_or_exit();
+                Backup::create(&passphrase, &dirs, cache)
+                    .and_then(|backup| backup.save(&passphrase, &file))
+                    .unwrap_or_exit();
+            }
+            ConfigEnum::ImportBackup { file, conflict } => {
+                let backup_passphrase = scanpw!("Backup passphrase: ");
+                println!();
+                let backup = Backup::load(&backup_passphrase, &file).unwrap_or_exit();
+                let passphrase = if passfile.exists() {
+                    encryption::get_passphrase(&passfile).unwrap_or_exit()
+                } else {
+                    debug!("import-backup: no passfile, using backup passphrase");
+                    encryption::encrypt(
+                        &backup_passphrase,
+                        backup_passphrase.as_bytes(),
+                        &passfile,
+                    )
+                    .unwrap_or_exit();
+                    backup_passphrase
+                };
+                backup
+                    .restore(&passphrase, &dirs, conflict)
+                    .unwrap_or_exit();
+            }
             ConfigEnum::Passphrase => {
                 encryption::set_passphrase(&dirs.data.join("passphrase.gpg")).unwrap_or_exit();
             }
-            ConfigEnum::Credentials { user } => {
-                register_credentials(
+            ConfigEnum::Credentials(command) => {
+                credentials_command(
                     &encryption::get_passphrase(&passfile).unwrap_or_exit(),
-                    user,
+                    command,
                     &dirs.data.join("credentials"),
                 )
                 .unwrap_or_exit();
@@ -147,12 +279,8 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn register_credentials(
-    passphrase: &str,
-    user: Option<String>,
-    dir: &Path,
-) -> anyhow::Result<String> {
-    let user: String = user.unwrap_or_else(|| {
+fn ask_user(user: Option<String>) -> String {
+    user.unwrap_or_else(|| {
         print!("Enter user to register credentials: ");
         io::stdout()
             .flush()
@@ -162,95 +290,331 @@ fn register_credentials(
             .read_line(&mut buffer)
             .unwrap_or_else(|error| fatal!("{error}"));
         buffer.trim().to_string()
-    });
-    let file = dir.join(&user);
-    encryption::edit(&file, passphrase)?;
+    })
+}
+
+/// Credentials file of `user`, which must be a plain file name
+fn user_file(dir: &Path, user: &str) -> anyhow::Result<PathBuf> {
+    credentials::check_user(user)?;
+    Ok(dir.join(user))
+}
+
+fn register_credentials(
+    passphrase: &str,
+    user: Option<String>,
+    dir: &Path,
+) -> anyhow::Result<String> {
+    let user = ask_user(user);
+    let file = user_file(dir, &user)?;
+    let mut credentials = Credentials::load(passphrase, &file)?;
+    if credentials.passwords.is_empty() {
+        debug!("no passwords registered for {user}, asking for one");
+        let password = scanpw!("Password for {user}: ");
+        println!();
+        credentials.add(Credential::new(password), None);
+        credentials.save(passphrase, &file)?;
+    }
     Ok(user)
 }
 
-fn get_cached_file(
-    args: &ConnectionArgs,
+fn credentials_command(
+    passphrase: &str,
+    command: CredentialsEnum,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    match command {
+        CredentialsEnum::List { user: None, .. } => {
+            for user in Credentials::users(&dir.to_path_buf())? {
+                println!("{user}");
+            }
+        }
+        CredentialsEnum::List {
+            user: Some(user),
+            show,
+        } => {
+            let file = user_file(dir, &user)?;
+            if !file.exists() {
+                bail!("no credentials registered for {user}");
+            }
+            let credentials = Credentials::load(passphrase, &file)?;
+            for (index, credential) in credentials.passwords.iter().enumerate() {
+                let password = if show {
+                    credential.password.clone()
+                } else {
+                    "*".repeat(8)
+                };
+                println!(
+                    "{index}\t{}\t{password}\t{}",
+                    credential.label,
+                    credential.created.format("%Y-%m-%d %H:%M")
+                );
+                if !credential.hosts.is_empty() {
+                    println!("\thosts: {}", credential.hosts.join(", "));
+                }
+                if !credential.groups.is_empty() {
+                    println!("\tgroups: {}", credential.groups.join(", "));
+                }
+                if !credential.notes.is_empty() {
+                    println!("\tnotes: {}", credential.notes);
+                }
+            }
+        }
+        CredentialsEnum::Add {
+            user,
+            label,
+            hosts,
+            groups,
+            notes,
+            position,
+        } => {
+            let user = ask_user(user);
+            let file = user_file(dir, &user)?;
+            let mut credentials = Credentials::load(passphrase, &file)?;
+            let password = scanpw!("Password for {user}: ");
+            println!();
+            credentials.add(
+                Credential {
+                    label,
+                    hosts,
+                    groups,
+                    notes,
+                    ..Credential::new(password)
+                },
+                position,
+            );
+            credentials.save(passphrase, &file)?;
+        }
+        CredentialsEnum::Remove { user, password } => {
+            let file = user_file(dir, &user)?;
+            let mut credentials = Credentials::load(passphrase, &file)?;
+            let removed = credentials.remove(&password)?;
+            debug!("removed password '{}' from {user}", removed.label);
+            credentials.save(passphrase, &file)?;
+        }
+        CredentialsEnum::Edit { user } => {
+            let user = ask_user(user);
+            Credentials::edit(passphrase, &user_file(dir, &user)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn preview_import(entries: &[ImportEntry], default_user: &str) {
+    println!("USER\tLABEL\tHOSTS");
+    for entry in entries {
+        let user = if entry.user.is_empty() {
+            default_user
+        } else {
+            &entry.user
+        };
+        println!(
+            "{user}\t{}\t{}",
+            entry.credential.label,
+            entry.credential.hosts.join(",")
+        );
+    }
+    println!("{} passwords would be imported", entries.len());
+}
+
+fn import_credentials(
+    passphrase: &str,
+    entries: Vec<ImportEntry>,
+    default_user: &str,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let mut users: BTreeMap<String, Vec<Credential>> = BTreeMap::new();
+    for entry in entries {
+        let user = if entry.user.is_empty() {
+            default_user.to_string()
+        } else {
+            entry.user
+        };
+        credentials::check_user(&user)?;
+        users.entry(user).or_default().push(entry.credential);
+    }
+    for (user, imported) in users {
+        let file = dir.join(&user);
+        let mut credentials = Credentials::load(passphrase, &file)?;
+        let mut count = 0;
+        for credential in imported {
+            let label = credential.label.clone();
+            if credentials.merge(credential) {
+                count += 1;
+            } else {
+                debug!("skipping already imported password '{label}'");
+            }
+        }
+        if count > 0 {
+            credentials.save(passphrase, &file)?;
+        }
+        println!("imported {count} passwords for {user}");
+    }
+    Ok(())
+}
+
+fn cache_command(
+    passfile: &PathBuf,
+    command: CacheEnum,
     config: &Config,
     dirs: &ConfigDirs,
-) -> anyhow::Result<PathBuf> {
+) -> anyhow::Result<()> {
+    let expire_time = cache::parse_duration(&config.cached_remote_password_expire_time)?;
+    let passphrase = encryption::get_passphrase(passfile)?;
+    let mut cache = PasswordCache::open(&passphrase, &dirs.state)?;
+    match command {
+        CacheEnum::List => {
+            println!("HOST\tUSER\tPORT\tAGE\tEXPIRED");
+            for (key, cached) in cache.entries() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    key.host,
+                    key.user,
+                    key.port,
+                    cache::format_duration(cached.age()),
+                    if cached.is_expired(expire_time) {
+                        "yes"
+                    } else {
+                        "no"
+                    }
+                );
+            }
+        }
+        CacheEnum::Show {
+            remote,
+            login_name,
+            port,
+        } => {
+            let entries = cache
+                .keys_for_host(&remote)
+                .iter()
+                .filter(|x| login_name.as_ref().is_none_or(|user| &x.user == user))
+                .filter(|x| port.is_none_or(|port| x.port == port))
+                .collect::<Vec<_>>();
+            if entries.is_empty() {
+                bail!(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no cached passwords for {remote}")
+                ));
+            }
+            for key in entries {
+                println!("{key}\t{}", cache.get(key).unwrap().password);
+            }
+        }
+        CacheEnum::Rm { pattern } => {
+            let pattern = glob::Pattern::new(&pattern)?;
+            let keys = cache
+                .entries()
+                .into_iter()
+                .filter(|(key, _)| key.matches(&pattern))
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            for key in keys {
+                debug!("removing cached password: {key}");
+                cache.remove(&key);
+                println!("removed {key}");
+            }
+        }
+        CacheEnum::Prune => {
+            let keys = cache
+                .entries()
+                .into_iter()
+                .filter(|(_, cached)| cached.is_expired(expire_time))
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            for key in keys {
+                debug!("pruning expired cached password: {key}");
+                cache.remove(&key);
+                println!("removed {key}");
+            }
+        }
+    }
+    cache.save(&passphrase)
+}
+
+fn get_cached_key(
+    args: &ConnectionArgs,
+    config: &Config,
+    cache: &PasswordCache,
+) -> Option<CacheKey> {
     let user = args
         .login_name
         .clone()
         .unwrap_or(config.default_login_user.clone());
     let port = args.port.unwrap_or(config.default_login_port);
-    let credentials = dirs.state.join(format!("{user}@{}:{port}", args.remote));
-    trace!("strict cached filed path: {credentials:?}");
-    if credentials.exists() {
-        debug!("found strict match cache: {credentials:?}");
-        return Ok(credentials);
-    }
-    let user_glob = if args.login_name.is_some() {
-        &user
+    let key = CacheKey::new(&user, &args.remote, port);
+    trace!("strict cache key: {key}");
+    if cache.get(&key).is_some() {
+        debug!("found strict match cache: {key}");
+        return Some(key);
+    }
+    let keys = cache
+        .keys_for_host(&args.remote)
+        .iter()
+        .filter(|x| args.login_name.is_none() || x.user == user)
+        .filter(|x| args.port.is_none() || x.port == port)
+        .collect::<Vec<&CacheKey>>();
+    trace!("loose cache matches: {keys:?}");
+    if let Some(key) = keys.iter().find(|x| x.user == user) {
+        debug!("found default user cache: {key}");
+        Some((*key).clone())
+    } else if let Some(key) = keys.first() {
+        debug!("found loose cache: {key}");
+        Some((*key).clone())
     } else {
-        "*"
-    };
-    let port_glob = if args.port.is_some() {
-        &port.to_string()
+        debug!("no cached password for {}", args.remote);
+        None
+    }
+}
+
+/// Groups of the host in the default inventory
+fn inventory_groups(host: &str, config: &Config) -> Vec<String> {
+    match config.default_inventory.as_ref() {
+        Some(inventory) => Inventory::load(inventory)
+            .map(|x| x.groups_of(host))
+            .unwrap_or_else(|error| {
+                warn!("unable to load inventory {inventory:?}: {error}");
+                Vec::new()
+            }),
+        None => Vec::new(),
+    }
+}
+
+fn get_scope(host: &str, address: &str, port: u16, config: &Config) -> Scope {
+    let groups = inventory_groups(host, config);
+    let addrs = if let Ok(addr) = address.parse::<IpAddr>() {
+        vec![addr]
+    } else if config
+        .credential_rules
+        .iter()
+        .any(|x| !x.networks.is_empty())
+    {
+        debug!("resolving {address} to match credential networks");
+        (address, port)
+            .to_socket_addrs()
+            .map(|x| x.map(|x| x.ip()).collect())
+            .unwrap_or_default()
     } else {
-        "*"
+        Vec::new()
     };
-    let glob_pattern = dirs
-        .state
-        .join(format!("{}@{}:{}", &user_glob, args.remote, &port_glob))
-        .into_os_string()
-        .into_string()
-        .unwrap();
-    trace!("glob pattern: {glob_pattern}");
-    let files: Vec<PathBuf> = glob(&glob_pattern)?
-        .map(|x| x.unwrap_or_else(|err| fatal!("{err}")))
-        .collect();
-    trace!("glob results: {files:?}");
-    if files.is_empty() {
-        debug!("no cached files with loose globbing");
-        bail!(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "credentials cache not found"
-        ));
-    } else {
-        let prefix_path = dirs
-            .state
-            .join(format!("{user}@"))
-            .into_os_string()
-            .into_string()
-            .unwrap();
-        let cache: Vec<PathBuf> = files
-            .clone()
-            .into_iter()
-            .filter(|file| {
-                file.file_name()
-                    .unwrap()
-                    .to_os_string()
-                    .into_string()
-                    .unwrap()
-                    .starts_with(&prefix_path)
-            })
-            .collect();
-        trace!("filter prefix: {prefix_path}");
-        if !cache.is_empty() {
-            debug!("found default user cache: {:?}", cache[0]);
-            Ok(cache[0].clone())
-        } else {
-            debug!("found loose cache: {:?}", files[0]);
-            Ok(files[0].clone())
-        }
+    Scope {
+        host: host.to_string(),
+        addrs,
+        groups,
     }
 }
 
-fn get_password(
+async fn get_password(
     passphrase: &str,
     args: &ConnectionArgs,
+    (user, address, port): (&str, &str, u16),
     config: &Config,
     dirs: &ConfigDirs,
-    cache: Option<&PathBuf>,
-) -> anyhow::Result<String> {
-    if let Some(cache) = cache {
-        debug!("decrypting password cache: {cache:?}");
-        encryption::decrypt(passphrase, cache)
+    cached: Option<&CachedPassword>,
+    jump: Option<&Session>,
+) -> anyhow::Result<(String, PasswordSource)> {
+    if let Some(cached) = cached {
+        debug!("using cached password");
+        Ok((cached.password.clone(), PasswordSource::Cached))
     } else {
         if args.cache {
             debug!("forced cache usage but cache was not found");
@@ -259,110 +623,952 @@ fn get_password(
                 "credentials cache not found"
             ));
         }
-        let user = args
-            .login_name
-            .clone()
-            .unwrap_or(config.default_login_user.clone());
-        let credentials = dirs.data.join("credentials").join(&user);
+        let credentials = user_file(&dirs.data.join("credentials"), user)?;
         trace!("saved credentials: {credentials:?}");
-        if credentials.exists() {
-            debug!("credentials found, testing passwords");
-            // TODO: password detection
-            let password = encryption::decrypt(passphrase, &credentials)?;
-            Ok(password)
-        } else {
-            debug!("no cache or credentials found, asking user for password");
-            let password = scanpw!("Password: ");
-            println!();
-            Ok(password)
+        let scope = get_scope(&args.remote, address, port, config);
+        trace!("credentials scope: {scope:?}");
+        let passwords = Credentials::load(passphrase, &credentials)?
+            .candidates(user, &scope, &config.credential_rules)
+            .into_iter()
+            .map(|x| x.password.clone())
+            .collect::<Vec<String>>();
+        if passwords.len() == 1 {
+            debug!("single password found, skipping detection");
+            return Ok((passwords[0].clone(), PasswordSource::Detected));
+        }
+        if !passwords.is_empty() {
+            debug!("credentials found, testing {} passwords", passwords.len());
+            match Session::detect_password(user, &passwords, (address, port), jump).await? {
+                Some(index) => return Ok((passwords[index].clone(), PasswordSource::Detected)),
+                None => warn!(
+                    "no stored password for {user} was accepted by {}",
+                    args.remote
+                ),
+            }
         }
+        debug!("no cache or valid credentials found, asking user for password");
+        let password = prompt_password(format!("{user}@{}'s password: ", args.remote)).await?;
+        Ok((password, PasswordSource::Asked))
     }
 }
 
 fn get_connection_data(
     args: &ConnectionArgs,
     config: &Config,
-    cache: Option<&PathBuf>,
-) -> anyhow::Result<(String, u16)> {
-    let user = if let Some(cache) = cache.as_ref() {
-        debug!("getting user from cache");
-        cache
-            .file_name()
-            .unwrap()
-            .to_os_string()
-            .into_string()
-            .unwrap()
-            .split_once("@")
-            .unwrap()
-            .0
-            .to_string()
-    } else {
-        debug!("getting user from args/config");
-        args.login_name
-            .clone()
-            .unwrap_or(config.default_login_user.clone())
-    };
-    let port = if let Some(cache) = cache.as_ref() {
-        debug!("getting port from cache");
-        cache
-            .file_name()
-            .unwrap()
-            .to_os_string()
-            .into_string()
-            .unwrap()
-            .rsplit_once(":")
-            .unwrap()
-            .1
-            .to_string()
-            .parse()?
+    cached: Option<&CacheKey>,
+) -> (String, u16) {
+    if let Some(key) = cached {
+        debug!("getting user and port from cache");
+        (key.user.clone(), key.port)
     } else {
-        debug!("getting port from args/config");
-        args.port.unwrap_or(config.default_login_port)
-    };
-    Ok((user, port))
+        debug!("getting user and port from args/config");
+        (
+            args.login_name
+                .clone()
+                .unwrap_or(config.default_login_user.clone()),
+            args.port.unwrap_or(config.default_login_port),
+        )
+    }
 }
 
-async fn ssh(
+/// Apply ssh config options missing from the command line and get the cached
+/// remote if any along with the login user and port
+fn get_target(
+    args: &mut ConnectionArgs,
+    host: &HostConfig,
+    config: &Config,
+    cache: &PasswordCache,
+) -> (Option<CacheKey>, String, u16) {
+    debug!("ssh config for {}: {host:?}", args.remote);
+    args.login_name = args.login_name.take().or(host.user.clone());
+    args.port = args.port.or(host.port);
+    let cached = get_cached_key(args, config, cache);
+    let (user, port) = get_connection_data(args, config, cached.as_ref());
+    (cached, user, port)
+}
+
+/// Apply ssh config options missing from the command line and resolve the
+/// password from the cache, the stored credentials or the user
+async fn login(
     passphrase: &str,
-    args: &ConnectionArgs,
+    args: &mut ConnectionArgs,
+    host: HostConfig,
     config: &Config,
     dirs: &ConfigDirs,
-) -> anyhow::Result<()> {
-    let cache = get_cached_file(args, config, dirs).ok();
-    let password = get_password(passphrase, args, config, dirs, cache.as_ref())?;
-    let (user, port) = get_connection_data(args, config, cache.as_ref())?;
-    if cache.is_none() || args.ask_pass {
+    cache: &Mutex<PasswordCache>,
+    jump: Option<&Session>,
+) -> anyhow::Result<Login> {
+    // the cache stays unlocked during password detection so other hosts can proceed
+    let (cached, user, port, cached_password) = {
+        let cache = cache.lock().await;
+        let (cached, user, port) = get_target(args, &host, config, &cache);
+        let password = cached.as_ref().and_then(|x| cache.get(x)).cloned();
+        (cached, user, port, password)
+    };
+    let address = host.hostname.unwrap_or(args.remote.clone());
+    let (password, source) = get_password(
+        passphrase,
+        args,
+        (&user, &address, port),
+        config,
+        dirs,
+        cached_password.as_ref(),
+        jump,
+    )
+    .await?;
+    if cached.is_none() || args.ask_pass {
         debug!(
             "writing password (cached: {}, ask_pass: {}) for {}@{}:{}",
-            cache.is_some(),
+            cached.is_some(),
             args.ask_pass,
             user,
             args.remote,
             port
         );
-        encryption::encrypt(
-            passphrase,
-            password.as_bytes(),
-            &dirs.state.join(format!("{user}@{}:{port}", args.remote)),
-        )?;
+        cache.lock().await.insert(
+            CacheKey::new(&user, &args.remote, port),
+            CachedPassword::new(&password),
+        );
     }
-    if cache.is_some() {
+    if cached.is_some() {
         debug!("password was cached, testing connectivity");
         // TODO: test ssh connection
     }
+    Ok(Login {
+        user,
+        address,
+        port,
+        password,
+        identity_files: host.identity_files,
+        source: Some(source),
+    })
+}
+
+/// Jump hosts from the command line, ssh config or asd config, in order
+fn get_jumps(args: &ConnectionArgs, host: &HostConfig, config: &Config) -> Vec<ConnectionArgs> {
+    let spec = args.jump.clone().or(host.proxy_jump.clone()).or_else(|| {
+        config
+            .proxy_jumps
+            .iter()
+            .filter(|(pattern, _)| {
+                glob::Pattern::new(pattern).is_ok_and(|x| x.matches(&args.remote))
+            })
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, jump)| jump.clone())
+    });
+    let Some(spec) = spec.filter(|x| x != "none") else {
+        return Vec::new();
+    };
+    trace!("jump hosts: {spec}");
+    spec.split(',')
+        .map(|destination| {
+            let (login_name, remote) = match destination.split_once('@') {
+                Some((user, remote)) => (Some(user.to_string()), remote),
+                None => (None, destination),
+            };
+            let (remote, port) = match remote.rsplit_once(':') {
+                Some((remote, port)) if port.parse::<u16>().is_ok() => (remote, port.parse().ok()),
+                _ => (remote, None),
+            };
+            ConnectionArgs {
+                remote: remote.to_string(),
+                login_name,
+                port,
+                cache: args.cache,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// Log into the jump hosts of the remote, in order, and resolve the remote login.
+/// Returns the jump host logins, the last jump session and the remote login.
+async fn login_chain(
+    passphrase: &str,
+    args: &mut ConnectionArgs,
+    ssh_config: &SshConfig,
+    config: &Config,
+    dirs: &ConfigDirs,
+    cache: &Mutex<PasswordCache>,
+) -> anyhow::Result<(Vec<Login>, Option<Session>, Login)> {
+    let host = ssh_config.resolve(&args.remote, args.login_name.as_deref());
+    let mut hops = Vec::new();
+    let mut jump = None;
+    for mut hop in get_jumps(args, &host, config) {
+        let hop_host = ssh_config.resolve(&hop.remote, hop.login_name.as_deref());
+        let login = login(
+            passphrase,
+            &mut hop,
+            hop_host,
+            config,
+            dirs,
+            cache,
+            jump.as_ref(),
+        )
+        .await?;
+        debug!(
+            "connecting to jump host {}@{}:{}",
+            login.user, login.address, login.port
+        );
+        jump = Some(
+            Session::connect(
+                &login.user,
+                &login.password,
+                &login.identity_files,
+                (&login.address, login.port),
+                jump,
+            )
+            .await
+            // the remote is unreachable through a hop that failed to connect
+            .map_err(|error| match error.downcast_ref::<ConnectError>() {
+                Some(ConnectError::AuthFailed) => error,
+                _ => ConnectError::Unreachable(format!("jump host {}: {error}", hop.remote)).into(),
+            })?,
+        );
+        hops.push(login);
+    }
+    let login = login(passphrase, args, host, config, dirs, cache, jump.as_ref()).await?;
+    Ok((hops, jump, login))
+}
+
+/// Open an interactive session, each connection is recorded in the audit log
+/// once it ends. With `--reconnect` a lost connection is opened again, retrying
+/// with a growing delay while the remote is unreachable up to the given attempts.
+async fn ssh(
+    passphrase: &str,
+    args: &mut ConnectionArgs,
+    config: &Config,
+    dirs: &ConfigDirs,
+) -> anyhow::Result<()> {
+    ssh::set_keepalive(config.keepalive()?);
+    let mut retries = None;
+    loop {
+        let start = Instant::now();
+        let mut entry = audit::Entry::new("ssh", &args.remote, SystemTime::now());
+        let result = ssh_session(passphrase, args, config, dirs, &mut entry).await;
+        // nothing connects with --print or --dry-run
+        if !args.print && !args.dry_run {
+            entry.success = result.is_ok();
+            if let Err(error) = &result {
+                entry.error = Some(ErrorClass::of(error));
+                entry.message = Some(error.to_string());
+            }
+            entry.duration = start.elapsed().as_secs_f64();
+            audit::write(&dirs.state, &entry);
+        }
+        let Err(error) = &result else {
+            return result;
+        };
+        let Some(attempts) = args.reconnect else {
+            return result;
+        };
+        let retry = match (error.downcast_ref(), retries) {
+            (Some(ConnectError::Disconnected), _) => 0,
+            (Some(ConnectError::Unreachable(_)), Some(retry)) => retry + 1,
+            _ => return result,
+        };
+        if retry >= attempts {
+            eprintln!("{error}, giving up after {attempts} attempts");
+            return result;
+        }
+        retries = Some(retry);
+        let delay = Duration::from_secs(1 << retry.min(5));
+        eprintln!("{error}, reconnecting in {}s", delay.as_secs());
+        tokio::time::sleep(delay).await;
+        // the password was cached by the first login
+        (args.cache, args.ask_pass, args.force) = (true, false, false);
+    }
+}
+
+async fn ssh_session(
+    passphrase: &str,
+    args: &mut ConnectionArgs,
+    config: &Config,
+    dirs: &ConfigDirs,
+    entry: &mut audit::Entry,
+) -> anyhow::Result<()> {
+    let ssh_config = SshConfig::load(&config.ssh_config_files, &dirs.home);
+    let cache = Mutex::new(PasswordCache::open(passphrase, &dirs.state)?);
+    let env = remote_env(
+        args,
+        &ssh_config.resolve(&args.remote, args.login_name.as_deref()),
+        config,
+    );
+    let (record, remote) = (args.record, args.remote.clone());
+    let options = |user: &str| ShellOptions {
+        env: env.clone(),
+        record: (record || recorded(&remote, user, config))
+            .then(|| (dirs.data.join("recordings"), user.into())),
+    };
+    // remote forwards are requested by the master itself, so they need their own connection
+    let socket = if (args.master || config.control_master)
+        && !args.print
+        && !args.dry_run
+        && args.remote_forward.is_empty()
+    {
+        let host = ssh_config.resolve(&args.remote, args.login_name.as_deref());
+        let (_, user, port) = get_target(args, &host, config, &*cache.lock().await);
+        let socket = control::socket_path(&dirs.state, &CacheKey::new(&user, &args.remote, port));
+        if let Some(ssh) = Session::connect_control(&socket, &user).await? {
+            (entry.user, entry.port, entry.auth) = (Some(user.clone()), Some(port), Some(ssh.auth));
+            return shell(args, ssh, options(&user)).await;
+        }
+        Some(socket)
+    } else {
+        None
+    };
+    let (mut hops, mut jump, login) =
+        login_chain(passphrase, args, &ssh_config, config, dirs, &cache).await?;
+    cache.lock().await.save(passphrase)?;
+    (entry.user, entry.port) = (Some(login.user.clone()), Some(login.port));
+    entry.password_source = login.source;
     if args.print {
-        println!("{password}");
+        println!("{}", login.password);
         return Ok(());
     }
     if args.dry_run {
         return Ok(());
     }
-    let mut ssh = Session::connect(user, password, (args.remote.clone(), port)).await?;
-    let code = {
-        let _raw_term = std::io::stdout().into_raw_mode()?;
-        ssh.call("$SHELL -l").await?
-    };
-    debug!("exit code {code}");
+    if let Some(socket) = socket {
+        hops.push(login.clone());
+        match control::spawn(&socket, &config.control_persist, &hops) {
+            Ok(()) => {
+                if let Some(mut jump) = jump.take() {
+                    jump.close().await?;
+                }
+                let ssh = Session::connect_control(&socket, &login.user)
+                    .await?
+                    .ok_or_else(|| anyhow!("master connection socket {socket:?} not found"))?;
+                entry.auth = Some(ssh.auth);
+                return shell(args, ssh, options(&login.user)).await;
+            }
+            Err(error) => warn!("unable to start master connection: {error}"),
+        }
+    }
+    let options = options(&login.user);
+    let ssh = Session::connect(
+        login.user,
+        login.password,
+        &login.identity_files,
+        (&login.address, login.port),
+        jump,
+    )
+    .await?;
+    entry.auth = Some(ssh.auth);
+    shell(args, ssh, options).await
+}
+
+/// Whether the config asks to record the sessions of `user` in the remote
+fn recorded(remote: &str, user: &str, config: &Config) -> bool {
+    if config.record_sessions.is_empty() {
+        return false;
+    }
+    let groups = inventory_groups(remote, config);
+    config
+        .record_sessions
+        .iter()
+        .any(|x| x.matches(user, remote, &groups))
+}
+
+/// Variables to send to a remote, the local ones matching `SendEnv` in the ssh
+/// config or `ssh_options` followed by the command line ones
+fn remote_env(args: &ConnectionArgs, host: &HostConfig, config: &Config) -> Vec<(String, String)> {
+    let patterns = config
+        .ssh_option("SendEnv")
+        .iter()
+        .flat_map(|x| x.split_whitespace())
+        .map(str::to_string)
+        .chain(host.send_env.iter().cloned())
+        .collect::<Vec<String>>();
+    environment::collect(&patterns, &args.env)
+}
+
+/// Settings of the session opened by `shell`
+struct ShellOptions {
+    env: Vec<(String, String)>,
+    /// Recordings directory and login user if the session is recorded
+    record: Option<(PathBuf, String)>,
+}
+
+/// Start the requested forwards and open a login shell, or wait for an
+/// interrupt with `--no-command`
+async fn shell(
+    args: &ConnectionArgs,
+    mut ssh: Session,
+    options: ShellOptions,
+) -> anyhow::Result<()> {
+    ssh.set_env(options.env);
+    let forwards = [
+        args.local_forward.as_slice(),
+        &args.remote_forward,
+        &args.dynamic_forward,
+    ]
+    .concat();
+    ssh.forward(&forwards).await?;
+    if args.no_command {
+        debug!("forwarding {} ports, waiting for interrupt", forwards.len());
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = ssh.wait_closed() => warn!("connection closed by remote"),
+        }
+    } else {
+        if let Some((dir, user)) = &options.record {
+            let size = termion::terminal_size()?;
+            let recording = Recording::create(dir, user, &args.remote, size)?;
+            eprintln!("Recording session to {:?}", recording.path());
+            ssh.record(recording);
+        }
+        let code = ssh
+            .call(args.attach.map_or("$SHELL -l", |x| x.command()))
+            .await?;
+        debug!("exit code {code}");
+    }
     ssh.close().await?;
     Ok(())
 }
+
+/// State shared by the hosts of a multi-host command
+struct Context {
+    passphrase: String,
+    config: Config,
+    dirs: ConfigDirs,
+    ssh_config: SshConfig,
+    cache: Mutex<PasswordCache>,
+    /// Resolved targets of the hosts, for machine readable results
+    targets: std::sync::Mutex<HashMap<String, Target>>,
+    /// Subcommand and commands run, for the audit log
+    subcommand: &'static str,
+    commands: Vec<String>,
+}
+
+impl Context {
+    fn new(
+        passfile: &PathBuf,
+        config: Config,
+        dirs: ConfigDirs,
+        subcommand: &'static str,
+    ) -> anyhow::Result<Self> {
+        let passphrase = encryption::get_passphrase(passfile)?;
+        ssh::set_keepalive(config.keepalive()?);
+        Ok(Self {
+            cache: Mutex::new(PasswordCache::open(&passphrase, &dirs.state)?),
+            ssh_config: SshConfig::load(&config.ssh_config_files, &dirs.home),
+            passphrase,
+            config,
+            dirs,
+            targets: Default::default(),
+            subcommand,
+            commands: Vec::new(),
+        })
+    }
+
+    /// Open a session to one of the hosts, through its master connection if one is running
+    async fn connect(&self, mut args: ConnectionArgs) -> anyhow::Result<Session> {
+        let host = self
+            .ssh_config
+            .resolve(&args.remote, args.login_name.as_deref());
+        let (_, user, port) = get_target(&mut args, &host, &self.config, &*self.cache.lock().await);
+        let env = remote_env(&args, &host, &self.config);
+        self.targets.lock().unwrap().insert(
+            args.remote.clone(),
+            Target {
+                user: user.clone(),
+                port,
+                auth: None,
+                source: None,
+            },
+        );
+        let socket =
+            control::socket_path(&self.dirs.state, &CacheKey::new(&user, &args.remote, port));
+        if let Some(mut session) = Session::connect_control(&socket, &user).await? {
+            session.set_env(env);
+            self.connected(&args.remote, &session);
+            return Ok(session);
+        }
+        let (_, jump, login) = login_chain(
+            &self.passphrase,
+            &mut args,
+            &self.ssh_config,
+            &self.config,
+            &self.dirs,
+            &self.cache,
+        )
+        .await?;
+        if let Some(target) = self.targets.lock().unwrap().get_mut(&args.remote) {
+            target.source = login.source;
+        }
+        let mut session = Session::connect(
+            login.user,
+            login.password,
+            &login.identity_files,
+            (&login.address, login.port),
+            jump,
+        )
+        .await?;
+        session.set_env(env);
+        self.connected(&args.remote, &session);
+        Ok(session)
+    }
+
+    /// User to become on the remote along with the password answering its prompt,
+    /// `None` without `--become`
+    async fn escalation(&self, remote: &str, args: &BecomeArgs) -> anyhow::Result<Option<Become>> {
+        if !args.enabled {
+            return Ok(None);
+        }
+        let target = self
+            .targets
+            .lock()
+            .unwrap()
+            .get(remote)
+            .cloned()
+            .unwrap_or_default();
+        // sudo asks for the password of the login user and su for the become user one
+        let user = match args.become_method {
+            BecomeMethod::Sudo => target.user.clone(),
+            BecomeMethod::Su => args.become_user.clone(),
+        };
+        let password = self
+            .become_password(remote, &user, target.port, args.become_method)
+            .await?;
+        Ok(Some(Become {
+            method: args.become_method,
+            user: args.become_user.clone(),
+            password,
+        }))
+    }
+
+    /// Password of `user` looked up apart from the login, which may have used a
+    /// key: the cached login password, the stored credentials or the user
+    async fn become_password(
+        &self,
+        remote: &str,
+        user: &str,
+        port: u16,
+        method: BecomeMethod,
+    ) -> anyhow::Result<String> {
+        let key = CacheKey::new(user, remote, port);
+        if let Some(cached) = self.cache.lock().await.get(&key) {
+            debug!("using cached password of {key} for {method}");
+            return Ok(cached.password.clone());
+        }
+        let address = self.ssh_config.resolve(remote, None).hostname;
+        let scope = get_scope(
+            remote,
+            address.as_deref().unwrap_or(remote),
+            port,
+            &self.config,
+        );
+        let credentials = user_file(&self.dirs.data.join("credentials"), user)?;
+        let credentials = Credentials::load(&self.passphrase, &credentials)?;
+        let candidates = credentials.candidates(user, &scope, &self.config.credential_rules);
+        if let Some(credential) = candidates.first() {
+            if candidates.len() > 1 {
+                debug!(
+                    "{} stored passwords for {user}, using the first",
+                    candidates.len()
+                );
+            }
+            return Ok(credential.password.clone());
+        }
+        prompt_password(format!("[{method}] password for {user}@{remote}: ")).await
+    }
+
+    fn connected(&self, remote: &str, session: &Session) {
+        if let Some(target) = self.targets.lock().unwrap().get_mut(remote) {
+            target.auth = Some(session.auth);
+        }
+    }
+
+    /// Machine readable result of a host, `output` adds the task specific fields
+    fn report<T>(&self, result: &HostResult<T>, output: impl Fn(Report, &T) -> Report) -> Report {
+        let report = Report::new(result, self.targets.lock().unwrap().get(&result.host));
+        match &result.result {
+            Ok(value) => output(report, value),
+            Err(_) => report,
+        }
+    }
+
+    /// Append the result of a host to the audit log
+    fn audit(&self, report: &Report) {
+        let duration = Duration::from_secs_f64(report.duration);
+        let mut entry =
+            audit::Entry::new(self.subcommand, &report.host, SystemTime::now() - duration);
+        entry.user = report.user.clone();
+        entry.port = report.port;
+        entry.auth = report.auth;
+        entry.password_source = self
+            .targets
+            .lock()
+            .unwrap()
+            .get(&report.host)
+            .and_then(|x| x.source);
+        entry.success = report.error.is_none();
+        entry.error = report.error;
+        entry.message = report.message.clone();
+        entry.duration = report.duration;
+        entry.commands = self.commands.clone();
+        audit::write(&self.dirs.state, &entry);
+    }
+}
+
+/// Hosts of an inventory, erroring if there are none
+fn inventory_hosts(inventory: &str) -> anyhow::Result<Vec<String>> {
+    let hosts = Inventory::load(inventory)?.hosts().to_vec();
+    if hosts.is_empty() {
+        bail!(anyhow!("no hosts found in inventory {inventory:?}"));
+    }
+    Ok(hosts)
+}
+
+/// Run `task` on every host in parallel, `print` is called with each result as
+/// hosts finish, or in inventory order with `--ordered`. With a JSON output
+/// format the results are printed as reports instead, completed by `report`.
+async fn run_hosts<T, F, Fut>(
+    hosts: &[String],
+    options: &RunnerArgs,
+    context: Arc<Context>,
+    task: F,
+    print: impl Fn(&str, &anyhow::Result<T>),
+    report: impl Fn(Report, &T) -> Report,
+) -> anyhow::Result<Vec<HostResult<T>>>
+where
+    T: Send + 'static,
+    F: Fn(Arc<Context>, String) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>> + Send + 'static,
+{
+    let forks = options.forks.unwrap_or(context.config.forks);
+    let timeout = options
+        .timeout
+        .as_ref()
+        .unwrap_or(&context.config.host_timeout);
+    let runner = Runner::new(forks, cache::parse_duration(timeout)?);
+    debug!("running on {} hosts with {forks} forks", hosts.len());
+    let show = |result: &HostResult<T>| match options.output {
+        Format::Text => print(&result.host, &result.result),
+        Format::Ndjson => report::print_ndjson(&context.report(result, &report)),
+        Format::Json => {}
+    };
+    let finished = |result: &HostResult<T>| {
+        context.audit(&context.report(result, &report));
+        if !options.ordered {
+            show(result)
+        }
+    };
+    let results = runner
+        .run(hosts, |host| task(context.clone(), host), finished)
+        .await;
+    match options.output {
+        Format::Json => {
+            let reports = results
+                .iter()
+                .map(|x| context.report(x, &report))
+                .collect::<Vec<Report>>();
+            report::print_json(&reports);
+        }
+        _ if options.ordered => results.iter().for_each(show),
+        _ => {}
+    }
+    context.cache.lock().await.save(&context.passphrase)?;
+    Ok(results)
+}
+
+/// Error if any of the hosts failed
+fn check_results<T>(results: &[HostResult<T>], failed: impl Fn(&T) -> bool) -> anyhow::Result<()> {
+    let count = results
+        .iter()
+        .filter(|x| x.result.as_ref().is_ok_and(&failed) || x.result.is_err())
+        .count();
+    if count > 0 {
+        bail!(anyhow!("{count} of {} hosts failed", results.len()));
+    }
+    Ok(())
+}
+
+/// Commands run by `exec` as written in the audit log, a script by its path
+fn executed(args: &ExecuteArgs) -> Vec<String> {
+    match &args.execute {
+        Some(path) => {
+            let args = args.args.as_deref().unwrap_or_default();
+            [format!("-x {}", path.display())]
+                .into_iter()
+                .chain(args.iter().cloned())
+                .collect()
+        }
+        None => args.commands.clone().unwrap_or_default(),
+    }
+}
+
+fn print_error(host: &str, error: &anyhow::Error) {
+    eprintln!("{host} | FAILED | {error}");
+}
+
+async fn exec(args: ExecuteArgs, context: Arc<Context>) -> anyhow::Result<()> {
+    let script = match &args.execute {
+        Some(path) => Some(Script::load(
+            path,
+            args.interpreter.clone(),
+            args.args.as_deref().unwrap_or_default(),
+        )?),
+        None => None,
+    };
+    // scripts are uploaded to each host unless streamed through stdin
+    let (command, stdin) = match &script {
+        Some(script) if args.stdin => (script.piped(), Some(script.data().to_vec())),
+        Some(_) => (String::new(), None),
+        None => (args.commands.clone().unwrap_or_default().join("\n"), None),
+    };
+    let upload = script.filter(|_| !args.stdin).map(Arc::new);
+    let (command, stdin) = (Arc::new(command), Arc::new(stdin));
+    let dry_run = args.dry_run;
+    let login_name = args.login_name.clone();
+    let hosts = inventory_hosts(&args.inventory)?;
+    let mode = if args.runner.output != Format::Text {
+        Mode::Buffer
+    } else if args.collapse {
+        Mode::Collapse
+    } else if args.tree {
+        Mode::Tree
+    } else {
+        Mode::Lines
+    };
+    let printer = Arc::new(Printer::new(&hosts, mode, args.out_dir.clone()));
+    let results = run_hosts(
+        &hosts,
+        &args.runner,
+        context,
+        |context, host| {
+            let (command, stdin, login_name) = (command.clone(), stdin.clone(), login_name.clone());
+            let (printer, escalation, upload) =
+                (printer.clone(), args.escalation.clone(), upload.clone());
+            let env = args.env.clone();
+            async move {
+                let mut session = context
+                    .connect(ConnectionArgs {
+                        remote: host.clone(),
+                        login_name,
+                        env,
+                        ..Default::default()
+                    })
+                    .await?;
+                let output = if dry_run {
+                    None
+                } else {
+                    let escalation = context.escalation(&host, &escalation).await?;
+                    let uploaded = match &upload {
+                        Some(script) => {
+                            // a become user other than root needs to read the script
+                            let shared = escalation.as_ref().is_some_and(|x| x.user != "root");
+                            Some(script.upload(&session, shared).await?)
+                        }
+                        None => None,
+                    };
+                    let command = match (&upload, &uploaded) {
+                        (Some(script), Some(uploaded)) => script.uploaded(uploaded.path()),
+                        _ => command.to_string(),
+                    };
+                    trace!("remote command: {command}");
+                    let mut sink = printer.start(&host)?;
+                    let write = |stderr, data: &[u8]| sink.write(stderr, data);
+                    let code = match &escalation {
+                        Some(escalation) => {
+                            escalation::exec(
+                                &session,
+                                escalation,
+                                &command,
+                                stdin.as_deref(),
+                                write,
+                            )
+                            .await
+                        }
+                        None => session.exec(&command, stdin.as_deref(), write).await,
+                    };
+                    if let Some(uploaded) = uploaded {
+                        uploaded.remove().await;
+                    }
+                    Some(sink.finish(code?)?)
+                };
+                session.close().await?;
+                Ok(output)
+            }
+        },
+        |host, result| match result {
+            Ok(None) => println!("{host} | SUCCESS"),
+            Ok(Some(output)) => printer.finish(host, output),
+            Err(error) => printer.error(host, error),
+        },
+        |report, output| match output {
+            Some(output) => report.output(output),
+            None => report,
+        },
+    )
+    .await?;
+    script::wait_removals().await;
+    if mode == Mode::Collapse {
+        let outputs = results
+            .iter()
+            .filter_map(|x| match &x.result {
+                Ok(Some(output)) => Some((x.host.as_str(), output)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        printer.collapse(&outputs);
+    }
+    check_results(&results, |output| {
+        output.as_ref().is_some_and(|x| x.code != Some(0))
+    })
+}
+
+fn patterns(globs: &[String]) -> anyhow::Result<Vec<glob::Pattern>> {
+    globs
+        .iter()
+        .map(|x| glob::Pattern::new(x).map_err(|error| anyhow!("invalid glob {x:?}: {error}")))
+        .collect()
+}
+
+/// Transfer options of put and get
+fn transfer_options(args: &FileArgs) -> anyhow::Result<transfer::Options> {
+    Ok(transfer::Options {
+        recursive: args.recursive,
+        skip_unchanged: args.skip_unchanged,
+        checksum: args.checksum,
+        delete: args.delete,
+        verify: args.verify,
+        include: patterns(&args.include)?,
+        exclude: patterns(&args.exclude)?,
+        links: args.links,
+        template: None,
+        template_globs: Vec::new(),
+    })
+}
+
+async fn put(args: PutArgs, context: Arc<Context>) -> anyhow::Result<()> {
+    let PutArgs {
+        file: args,
+        template,
+        template_glob,
+    } = args;
+    let files = Arc::new(args.files.clone());
+    let dest = Arc::new(args.dest.clone().unwrap_or(".".to_string()));
+    for file in files.iter() {
+        if fs::symlink_metadata(file).is_err() {
+            bail!(anyhow!("{file:?} not found"));
+        }
+        if file.is_dir() && !args.recursive {
+            bail!(anyhow!("{file:?} is a directory, use --recursive"));
+        }
+    }
+    let options = transfer::Options {
+        template_globs: patterns(&template_glob)?,
+        ..transfer_options(&args)?
+    };
+    // templates are rendered with the variables of each host
+    let inventory = match template {
+        true => Some(Inventory::load(&args.inventory)?),
+        false => None,
+    };
+    let login_name = args.login_name.clone();
+    let progress = Progress::new(args.quiet || args.runner.output != Format::Text);
+    let results = run_hosts(
+        &inventory_hosts(&args.inventory)?,
+        &args.runner,
+        context,
+        |context, host| {
+            let (files, dest, login_name) = (files.clone(), dest.clone(), login_name.clone());
+            let escalation = args.escalation.clone();
+            let template = inventory.as_ref().map(|x| {
+                let mut vars = x.vars(&host);
+                vars.insert("host".to_string(), host.clone());
+                vars
+            });
+            let options = transfer::Options {
+                template,
+                ..options.clone()
+            };
+            let progress = progress.tracker(&host);
+            async move {
+                let mut session = context
+                    .connect(ConnectionArgs {
+                        remote: host.clone(),
+                        login_name,
+                        ..Default::default()
+                    })
+                    .await?;
+                let escalation = context.escalation(&host, &escalation).await?;
+                let sftp = session.sftp().await?;
+                let sent = transfer::put(
+                    &session,
+                    &sftp,
+                    &files,
+                    &dest,
+                    escalation.as_ref(),
+                    &options,
+                    &progress,
+                )
+                .await?;
+                sftp.close().await?;
+                session.close().await?;
+                Ok(sent)
+            }
+        },
+        |host, result| {
+            progress.print(|| match result {
+                Ok(sent) => println!("{host} | SUCCESS | sent {sent}"),
+                Err(error) => print_error(host, error),
+            })
+        },
+        |report, sent| report.bytes(sent.bytes),
+    )
+    .await;
+    progress.finish();
+    let results = results?;
+    check_results(&results, |_| false)
+}
+
+async fn get(args: FileArgs, context: Arc<Context>) -> anyhow::Result<()> {
+    if args.escalation.enabled {
+        bail!(anyhow!("--become is only supported by put"));
+    }
+    let files = Arc::new(args.files.clone());
+    let dest = PathBuf::from(args.dest.clone().unwrap_or(".".to_string()));
+    let options = Arc::new(transfer_options(&args)?);
+    let login_name = args.login_name.clone();
+    let progress = Progress::new(args.quiet || args.runner.output != Format::Text);
+    let results = run_hosts(
+        &inventory_hosts(&args.inventory)?,
+        &args.runner,
+        context,
+        |context, host| {
+            let (files, login_name, options) = (files.clone(), login_name.clone(), options.clone());
+            // each host gets its own directory so equally named files do not collide
+            let dest = dest.join(&host);
+            let progress = progress.tracker(&host);
+            async move {
+                let mut session = context
+                    .connect(ConnectionArgs {
+                        remote: host,
+                        login_name,
+                        ..Default::default()
+                    })
+                    .await?;
+                let sftp = session.sftp().await?;
+                let received =
+                    transfer::get(&session, &sftp, &files, &dest, &options, &progress).await?;
+                sftp.close().await?;
+                session.close().await?;
+                Ok(received)
+            }
+        },
+        |host, result| {
+            progress.print(|| match result {
+                Ok(received) => println!("{host} | SUCCESS | received {received}"),
+                Err(error) => print_error(host, error),
+            })
+        },
+        |report, received| report.bytes(received.bytes),
+    )
+    .await;
+    progress.finish();
+    let results = results?;
+    check_results(&results, |_| false)
+}
@@ -0,0 +1,253 @@
+use log::debug;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use termion::color;
+
+use crate::ssh::Output;
+
+/// ANSI colors for host names: cyan, green, yellow, magenta, blue and red
+const COLORS: [u8; 6] = [6, 2, 3, 5, 4, 1];
+
+/// How the output of each host is shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Host prefixed lines as they arrive
+    Lines,
+    /// Each host complete output after it finishes
+    Tree,
+    /// Each distinct output once with the hosts that produced it, after all finish
+    Collapse,
+    /// Keep the output without printing it, for machine readable results
+    Buffer,
+}
+
+/// Prints the output of commands run on many hosts
+pub struct Printer {
+    mode: Mode,
+    out_dir: Option<PathBuf>,
+    color: bool,
+    width: usize,
+}
+
+impl Printer {
+    /// Output is also written to `out_dir` if given
+    pub fn new(hosts: &[String], mode: Mode, out_dir: Option<PathBuf>) -> Self {
+        Self {
+            mode,
+            out_dir,
+            color: termion::is_tty(&io::stdout()),
+            width: hosts.iter().map(|x| x.len()).max().unwrap_or_default(),
+        }
+    }
+
+    fn host(&self, host: &str) -> String {
+        if !self.color {
+            return host.to_string();
+        }
+        let index = host.bytes().fold(0usize, |hash, x| {
+            hash.wrapping_mul(31).wrapping_add(x as usize)
+        });
+        format!(
+            "{}{host}{}",
+            color::Fg(color::AnsiValue(COLORS[index % COLORS.len()])),
+            color::Fg(color::Reset)
+        )
+    }
+
+    /// Host name padded to the longest host so prefixed lines stay aligned
+    fn prefix(&self, host: &str) -> String {
+        let padding = " ".repeat(self.width.saturating_sub(host.len()));
+        format!("{}{padding} | ", self.host(host))
+    }
+
+    /// Output sink for a host, creating its files in the output directory
+    pub fn start(self: &Arc<Self>, host: &str) -> io::Result<HostOutput> {
+        let files = match &self.out_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                let file = |stream| File::create(dir.join(format!("{host}.{stream}")));
+                Some((file("stdout")?, file("stderr")?))
+            }
+            None => None,
+        };
+        Ok(HostOutput {
+            printer: self.clone(),
+            host: host.to_string(),
+            lines: [Vec::new(), Vec::new()],
+            output: Output::default(),
+            files,
+        })
+    }
+
+    /// Show the exit code of a host, along with its output in tree mode
+    pub fn finish(&self, host: &str, output: &Output) {
+        match self.mode {
+            Mode::Lines => println!("{}rc={}", self.prefix(host), exit_code(output)),
+            Mode::Tree => print_block(&self.host(host), output),
+            Mode::Collapse | Mode::Buffer => {}
+        }
+    }
+
+    /// Print each distinct output once, headed by the compressed list of hosts
+    /// that produced it, in the order each output was first seen
+    pub fn collapse(&self, outputs: &[(&str, &Output)]) {
+        let mut groups: Vec<(Vec<&str>, &Output)> = Vec::new();
+        let mut seen = HashMap::new();
+        for (host, output) in outputs {
+            let key = (output.code, &output.stdout, &output.stderr);
+            let index = *seen.entry(key).or_insert_with(|| {
+                groups.push((Vec::new(), output));
+                groups.len() - 1
+            });
+            groups[index].0.push(host);
+        }
+        debug!(
+            "{} hosts produced {} distinct outputs",
+            outputs.len(),
+            groups.len()
+        );
+        for (hosts, output) in groups {
+            print_block(&compress_hosts(&hosts), output);
+        }
+    }
+
+    pub fn error(&self, host: &str, error: &anyhow::Error) {
+        eprintln!("{} | FAILED | {error}", self.host(host));
+    }
+}
+
+/// Output of a single host, printed line by line in lines mode and buffered otherwise
+pub struct HostOutput {
+    printer: Arc<Printer>,
+    host: String,
+    lines: [Vec<u8>; 2],
+    output: Output,
+    files: Option<(File, File)>,
+}
+
+fn exit_code(output: &Output) -> String {
+    output
+        .code
+        .map(|x| x.to_string())
+        .unwrap_or("?".to_string())
+}
+
+fn print_block(header: &str, output: &Output) {
+    println!("{header} | rc={} >>", exit_code(output));
+    let _ = io::stdout().write_all(&output.stdout);
+    let _ = io::stderr().write_all(&output.stderr);
+}
+
+/// Text before and after the last number of a host name, and the number width
+type RangeKey<'a> = (&'a str, &'a str, usize);
+
+/// Compress host names into ranges such as `web[01-40,42]`. Hosts are grouped
+/// by the text around their last number and the width of that number.
+pub fn compress_hosts(hosts: &[&str]) -> String {
+    let mut groups: Vec<(RangeKey, BTreeSet<u64>)> = Vec::new();
+    let mut plain = Vec::new();
+    for host in hosts {
+        let Some(end) = host.rfind(|x: char| x.is_ascii_digit()).map(|x| x + 1) else {
+            plain.push(host.to_string());
+            continue;
+        };
+        let start = host[..end]
+            .rfind(|x: char| !x.is_ascii_digit())
+            .map_or(0, |x| x + 1);
+        let Result::Ok(number) = host[start..end].parse::<u64>() else {
+            plain.push(host.to_string());
+            continue;
+        };
+        let key = (&host[..start], &host[end..], end - start);
+        match groups.iter_mut().find(|(x, _)| *x == key) {
+            Some((_, numbers)) => {
+                numbers.insert(number);
+            }
+            None => groups.push((key, BTreeSet::from([number]))),
+        }
+    }
+    let mut names = Vec::new();
+    for ((prefix, suffix, width), numbers) in groups {
+        if numbers.len() == 1 {
+            let number = numbers.first().unwrap();
+            names.push(format!("{prefix}{number:0width$}{suffix}"));
+            continue;
+        }
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for number in numbers {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == number => *end = number,
+                _ => ranges.push((number, number)),
+            }
+        }
+        let ranges = ranges
+            .into_iter()
+            .map(|(start, end)| match start == end {
+                true => format!("{start:0width$}"),
+                false => format!("{start:0width$}-{end:0width$}"),
+            })
+            .collect::<Vec<String>>();
+        names.push(format!("{prefix}[{}]{suffix}", ranges.join(",")));
+    }
+    names.extend(plain);
+    names.join(",")
+}
+
+impl HostOutput {
+    pub fn write(&mut self, stderr: bool, data: &[u8]) -> io::Result<()> {
+        if let Some((stdout_file, stderr_file)) = self.files.as_mut() {
+            if stderr { stderr_file } else { stdout_file }.write_all(data)?;
+        }
+        if self.printer.mode != Mode::Lines {
+            if stderr {
+                self.output.stderr.extend_from_slice(data);
+            } else {
+                self.output.stdout.extend_from_slice(data);
+            }
+            return Ok(());
+        }
+        let line = &mut self.lines[stderr as usize];
+        line.extend_from_slice(data);
+        // only complete lines are printed so hosts do not interleave mid line
+        if let Some(end) = line.iter().rposition(|x| *x == b'\n') {
+            let complete = line.drain(..=end).collect::<Vec<u8>>();
+            self.print(stderr, &complete)?;
+        }
+        Ok(())
+    }
+
+    fn print(&self, stderr: bool, data: &[u8]) -> io::Result<()> {
+        let prefix = self.printer.prefix(&self.host);
+        let mut text = Vec::new();
+        for line in data.split_inclusive(|x| *x == b'\n') {
+            text.extend_from_slice(prefix.as_bytes());
+            text.extend_from_slice(line);
+        }
+        if !text.ends_with(b"\n") {
+            text.push(b'\n');
+        }
+        if stderr {
+            io::stderr().lock().write_all(&text)
+        } else {
+            io::stdout().lock().write_all(&text)
+        }
+    }
+
+    /// Flush partial lines and return the buffered output with the exit code
+    pub fn finish(mut self, code: Option<u32>) -> io::Result<Output> {
+        for stderr in [false, true] {
+            let line = std::mem::take(&mut self.lines[stderr as usize]);
+            if !line.is_empty() {
+                self.print(stderr, &line)?;
+            }
+        }
+        if let Some(dir) = &self.printer.out_dir {
+            debug!("wrote {} output to {dir:?}", self.host);
+        }
+        self.output.code = code;
+        Ok(self.output)
+    }
+}
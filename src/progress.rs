@@ -0,0 +1,325 @@
+use log::trace;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use termion::{clear, cursor};
+use tokio::task::JoinHandle;
+
+use crate::cache::format_duration;
+
+/// Time between redraws of the progress bars
+const BAR_INTERVAL: Duration = Duration::from_millis(200);
+/// Time between progress lines when bars are not shown
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Columns of the bar itself
+const BAR_WIDTH: usize = 20;
+
+/// Progress of a host transfer
+struct Host {
+    name: String,
+    active: bool,
+    started: Instant,
+    /// Bytes of every file to transfer
+    expected: u64,
+    /// Bytes in place, including resumed parts
+    done: u64,
+    /// Bytes moved by this run, for the rate
+    sent: u64,
+    /// Name, size and bytes done of the file in flight
+    file: Option<(String, u64, u64)>,
+}
+
+impl Host {
+    fn rate(&self) -> f64 {
+        self.sent as f64 / self.started.elapsed().as_secs_f64().max(0.001)
+    }
+}
+
+struct State {
+    hosts: Vec<Host>,
+    /// Lines of bars on screen
+    drawn: usize,
+}
+
+/// Shows the progress of file transfers in many hosts, as bars redrawn in
+/// place on a terminal or as periodic lines otherwise
+pub struct Progress {
+    bars: bool,
+    started: Instant,
+    state: Mutex<State>,
+    ticker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Progress {
+    /// Bars are only drawn on a terminal and when not `quiet`
+    pub fn new(quiet: bool) -> Arc<Self> {
+        let bars = !quiet && termion::is_tty(&io::stdout()) && termion::is_tty(&io::stderr());
+        trace!("transfer progress with bars: {bars}");
+        let progress = Arc::new(Self {
+            bars,
+            started: Instant::now(),
+            state: Mutex::new(State {
+                hosts: Vec::new(),
+                drawn: 0,
+            }),
+            ticker: Mutex::new(None),
+        });
+        let ticker = progress.clone();
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(match ticker.bars {
+                true => BAR_INTERVAL,
+                false => LOG_INTERVAL,
+            });
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                ticker.tick();
+            }
+        });
+        *progress.ticker.lock().unwrap() = Some(handle);
+        progress
+    }
+
+    /// Start tracking the transfers of a host, until the tracker is dropped
+    pub fn tracker(self: &Arc<Self>, host: &str) -> Tracker {
+        let mut state = self.state.lock().unwrap();
+        state.hosts.push(Host {
+            name: host.to_string(),
+            active: true,
+            started: Instant::now(),
+            expected: 0,
+            done: 0,
+            sent: 0,
+            file: None,
+        });
+        Tracker {
+            progress: self.clone(),
+            index: state.hosts.len() - 1,
+        }
+    }
+
+    /// Run `print` with the bars cleared so its output is not overwritten
+    pub fn print(&self, print: impl FnOnce()) {
+        let mut state = self.state.lock().unwrap();
+        self.clear(&mut state);
+        print();
+        self.draw(&mut state);
+    }
+
+    /// Stop showing progress, removing the bars
+    pub fn finish(&self) {
+        if let Some(handle) = self.ticker.lock().unwrap().take() {
+            handle.abort();
+        }
+        let mut state = self.state.lock().unwrap();
+        self.clear(&mut state);
+    }
+
+    fn update(&self, index: usize, update: impl FnOnce(&mut Host)) {
+        update(&mut self.state.lock().unwrap().hosts[index]);
+    }
+
+    fn tick(&self) {
+        let mut state = self.state.lock().unwrap();
+        if self.bars {
+            self.clear(&mut state);
+            self.draw(&mut state);
+            return;
+        }
+        for host in state.hosts.iter().filter(|x| x.active) {
+            if let Some((file, size, done)) = &host.file {
+                eprintln!(
+                    "{} | PROGRESS | {file} {}",
+                    host.name,
+                    stats(*done, *size, host.rate())
+                );
+            }
+        }
+        if state.hosts.len() > 1 && state.hosts.iter().any(|x| x.active) {
+            let (done, expected, rate) = self.total(&state);
+            eprintln!("all hosts | PROGRESS | {}", stats(done, expected, rate));
+        }
+    }
+
+    /// Bytes done and expected in every host, with the overall rate
+    fn total(&self, state: &State) -> (u64, u64, f64) {
+        let done = state.hosts.iter().map(|x| x.done).sum();
+        let expected = state.hosts.iter().map(|x| x.expected).sum();
+        let sent = state.hosts.iter().map(|x| x.sent).sum::<u64>();
+        let rate = sent as f64 / self.started.elapsed().as_secs_f64().max(0.001);
+        (done, expected, rate)
+    }
+
+    fn clear(&self, state: &mut State) {
+        if !self.bars || state.drawn == 0 {
+            return;
+        }
+        let mut stderr = io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "{}\r{}",
+            cursor::Up(state.drawn as u16),
+            clear::AfterCursor
+        );
+        let _ = stderr.flush();
+        state.drawn = 0;
+    }
+
+    fn draw(&self, state: &mut State) {
+        if !self.bars || !state.hosts.iter().any(|x| x.active) {
+            return;
+        }
+        let width = match termion::terminal_size() {
+            Ok((width, _)) if width > 0 => width as usize,
+            _ => 80,
+        };
+        let label = state
+            .hosts
+            .iter()
+            .map(|x| x.name.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let mut lines = Vec::new();
+        for host in state.hosts.iter().filter(|x| x.active) {
+            if let Some((file, size, done)) = &host.file {
+                lines.push(bar(
+                    &host.name,
+                    label,
+                    file,
+                    *done,
+                    *size,
+                    host.rate(),
+                    width,
+                ));
+            }
+        }
+        if state.hosts.len() > 1 {
+            let (done, expected, rate) = self.total(state);
+            lines.push(bar("total", label, "", done, expected, rate, width));
+        }
+        let mut stderr = io::stderr().lock();
+        for line in &lines {
+            let _ = writeln!(stderr, "{line}");
+        }
+        let _ = stderr.flush();
+        state.drawn = lines.len();
+    }
+}
+
+/// Reports the transfers of a host to the shared `Progress`
+pub struct Tracker {
+    progress: Arc<Progress>,
+    index: usize,
+}
+
+impl Tracker {
+    /// Add bytes to transfer
+    pub fn expect(&self, bytes: u64) {
+        self.progress.update(self.index, |x| x.expected += bytes);
+    }
+
+    /// Remove bytes that do not need to be transferred after all
+    pub fn skip(&self, bytes: u64) {
+        self.progress.update(self.index, |x| {
+            x.expected = x.expected.saturating_sub(bytes)
+        });
+    }
+
+    /// Start a file, `offset` bytes of it are already in place
+    pub fn file(&self, name: &str, size: u64, offset: u64) {
+        self.progress.update(self.index, |x| {
+            x.file = Some((name.to_string(), size, offset));
+            x.done += offset;
+        });
+    }
+
+    /// Add bytes of the current file moved
+    pub fn add(&self, bytes: u64) {
+        self.progress.update(self.index, |x| {
+            if let Some((_, _, done)) = &mut x.file {
+                *done += bytes;
+            }
+            x.done += bytes;
+            x.sent += bytes;
+        });
+    }
+
+    /// The current file is complete
+    pub fn end_file(&self) {
+        self.progress.update(self.index, |x| x.file = None);
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.progress.update(self.index, |x| {
+            x.active = false;
+            x.file = None;
+        });
+    }
+}
+
+/// Human readable size in binary units
+fn size(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for next in units {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    match unit {
+        "B" => format!("{bytes} B"),
+        _ => format!("{value:.1} {unit}"),
+    }
+}
+
+/// Percentage, bytes, rate and estimated time left
+fn stats(done: u64, expected: u64, rate: f64) -> String {
+    let done = done.min(expected);
+    let percent = match expected {
+        0 => 100,
+        _ => done * 100 / expected,
+    };
+    let eta = match rate {
+        x if x >= 1.0 => format_duration(Duration::from_secs_f64((expected - done) as f64 / x)),
+        _ => "?".to_string(),
+    };
+    format!(
+        "{percent:>3}% {}/{} {}/s ETA {eta}",
+        size(done),
+        size(expected),
+        size(rate as u64)
+    )
+}
+
+/// A line with the host, the file and a bar, fitting in `width` columns
+fn bar(
+    host: &str,
+    label: usize,
+    file: &str,
+    done: u64,
+    expected: u64,
+    rate: f64,
+    width: usize,
+) -> String {
+    let filled = match expected {
+        0 => BAR_WIDTH,
+        _ => (done.min(expected) as f64 / expected as f64 * BAR_WIDTH as f64) as usize,
+    };
+    let bar = format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled));
+    let stats = stats(done, expected, rate);
+    // the file name gets whatever is left, keeping its end
+    let room = width.saturating_sub(label + bar.len() + stats.len() + 4);
+    let chars = file.chars().count();
+    let file = match chars > room {
+        true => file.chars().skip(chars - room).collect(),
+        false => file.to_string(),
+    };
+    let line = format!("{host:label$} {file:room$} {bar} {stats}");
+    line.chars().take(width.saturating_sub(1)).collect()
+}
@@ -0,0 +1,143 @@
+use anyhow::{anyhow, bail, Ok};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Output of an interactive session written as an asciicast v2 file. Only the
+/// output is recorded, keystrokes may hold passwords.
+pub struct Recording {
+    path: PathBuf,
+    file: BufWriter<File>,
+    start: Instant,
+    /// Trailing bytes of an incomplete UTF-8 character
+    pending: Vec<u8>,
+}
+
+impl Recording {
+    /// Start a recording of `user@remote` in `dir`, named after the current time
+    pub fn create(
+        dir: &Path,
+        user: &str,
+        remote: &str,
+        (width, height): (u16, u16),
+    ) -> anyhow::Result<Self> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let now = chrono::Local::now();
+        let path = dir.join(format!(
+            "{}-{user}@{remote}.cast",
+            now.format("%Y%m%d-%H%M%S")
+        ));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|error| anyhow!("unable to create recording {path:?}: {error}"))?;
+        let mut file = BufWriter::new(file);
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            "title": format!("{user}@{remote}"),
+            "env": {
+                "TERM": std::env::var("TERM").unwrap_or("xterm".into()),
+                "SHELL": std::env::var("SHELL").unwrap_or_default(),
+            },
+        });
+        writeln!(file, "{header}")?;
+        debug!("recording session to {path:?}");
+        Ok(Self {
+            path,
+            file,
+            start: Instant::now(),
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add output of the session
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Result::Ok(_) => self.pending.len(),
+            // an incomplete character at the end waits for the next output
+            Err(error) if error.error_len().is_none() => error.valid_up_to(),
+            Err(_) => self.pending.len(),
+        };
+        if valid == 0 {
+            return io::Result::Ok(());
+        }
+        let rest = self.pending.split_off(valid);
+        let text = String::from_utf8_lossy(&self.pending).to_string();
+        self.pending = rest;
+        let event = json!([self.start.elapsed().as_secs_f64(), "o", text]);
+        writeln!(self.file, "{event}")?;
+        self.file.flush()
+    }
+}
+
+/// Play an asciicast v2 file on the terminal, `speed` multiplies the pace and
+/// pauses are capped to `max_wait` if given
+pub async fn replay(path: &Path, speed: f64, max_wait: Option<Duration>) -> anyhow::Result<()> {
+    if speed <= 0.0 {
+        bail!(anyhow!("speed must be greater than zero"));
+    }
+    let file = File::open(path).map_err(|error| anyhow!("unable to open {path:?}: {error}"))?;
+    let mut lines = BufReader::new(file).lines();
+    let header: Value =
+        serde_json::from_str(&lines.next().ok_or_else(|| anyhow!("{path:?} is empty"))??)?;
+    if header["version"] != 2 {
+        bail!(anyhow!("{path:?} is not an asciicast v2 recording"));
+    }
+    debug!("replaying {path:?}: {header}");
+    let mut stdout = io::stdout();
+    let mut last = 0.0;
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Value = match serde_json::from_str(&line) {
+            Result::Ok(event) => event,
+            Err(error) => {
+                warn!("skipping invalid event at line {}: {error}", number + 2);
+                continue;
+            }
+        };
+        let (Some(time), Some(kind), Some(data)) =
+            (event[0].as_f64(), event[1].as_str(), event[2].as_str())
+        else {
+            warn!("skipping invalid event at line {}", number + 2);
+            continue;
+        };
+        if kind != "o" {
+            continue;
+        }
+        // an idle limit also bounds waits too long to represent
+        let wait = match (
+            Duration::try_from_secs_f64(((time - last) / speed).max(0.0)),
+            max_wait,
+        ) {
+            (Result::Ok(wait), Some(max_wait)) => wait.min(max_wait),
+            (Result::Ok(wait), None) => wait,
+            (Err(_), Some(max_wait)) => max_wait,
+            (Err(_), None) => bail!(anyhow!("invalid event time at line {}", number + 2)),
+        };
+        tokio::time::sleep(wait).await;
+        last = time;
+        stdout.write_all(data.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
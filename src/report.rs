@@ -0,0 +1,127 @@
+use clap::ValueEnum;
+use log::warn;
+use serde::Serialize;
+
+use crate::runner::{HostResult, Interrupted};
+use crate::ssh::{AuthMethod, ConnectError, Output, PasswordSource};
+
+/// Format of the results of commands run on many hosts
+#[derive(Debug, Clone, Copy, Default, ValueEnum, PartialEq)]
+pub enum Format {
+    /// Human readable output
+    #[default]
+    Text,
+    /// A JSON array with every host once all of them finished
+    Json,
+    /// A JSON object per line as each host finishes
+    Ndjson,
+}
+
+/// Why a host failed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Unreachable,
+    AuthFailed,
+    Timeout,
+    Cancelled,
+    CommandFailed,
+}
+
+impl ErrorClass {
+    /// Class of a host error, anything not related to the connection is a
+    /// command failure
+    pub fn of(error: &anyhow::Error) -> Self {
+        match error.downcast_ref() {
+            Some(Interrupted::Timeout(_)) => return Self::Timeout,
+            Some(Interrupted::Cancelled) => return Self::Cancelled,
+            None => {}
+        }
+        match error.downcast_ref() {
+            Some(ConnectError::Unreachable(_) | ConnectError::Disconnected) => Self::Unreachable,
+            Some(ConnectError::AuthFailed) => Self::AuthFailed,
+            None => Self::CommandFailed,
+        }
+    }
+}
+
+/// User and port resolved for a host, along with how it authenticated once connected
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    pub user: String,
+    pub port: u16,
+    pub auth: Option<AuthMethod>,
+    pub source: Option<PasswordSource>,
+}
+
+/// Machine readable result of a host
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub auth: Option<AuthMethod>,
+    pub exit_code: Option<u32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub bytes: Option<u64>,
+    /// Seconds the host took
+    pub duration: f64,
+    pub error: Option<ErrorClass>,
+    pub message: Option<String>,
+}
+
+impl Report {
+    pub fn new<T>(result: &HostResult<T>, target: Option<&Target>) -> Self {
+        let error = result.result.as_ref().err();
+        Self {
+            host: result.host.clone(),
+            user: target.map(|x| x.user.clone()),
+            port: target.map(|x| x.port),
+            auth: target.and_then(|x| x.auth),
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            bytes: None,
+            duration: result.duration.as_secs_f64(),
+            error: error.map(ErrorClass::of),
+            message: error.map(|x| x.to_string()),
+        }
+    }
+
+    /// Add the output of a command, a nonzero exit code is a command failure
+    pub fn output(mut self, output: &Output) -> Self {
+        self.exit_code = output.code;
+        self.stdout = Some(String::from_utf8_lossy(&output.stdout).to_string());
+        self.stderr = Some(String::from_utf8_lossy(&output.stderr).to_string());
+        if output.code != Some(0) {
+            self.error = Some(ErrorClass::CommandFailed);
+            self.message = Some(match output.code {
+                Some(code) => format!("command exited with code {code}"),
+                None => "command exited without status".to_string(),
+            });
+        }
+        self
+    }
+
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+}
+
+/// Print reports as a JSON array
+pub fn print_json(reports: &[Report]) {
+    match serde_json::to_string_pretty(reports) {
+        Ok(json) => println!("{json}"),
+        Err(error) => warn!("unable to serialize results: {error}"),
+    }
+}
+
+/// Print a report as a single JSON line
+pub fn print_ndjson(report: &Report) {
+    match serde_json::to_string(report) {
+        Ok(json) => println!("{json}"),
+        Err(error) => warn!("unable to serialize result of {}: {error}", report.host),
+    }
+}
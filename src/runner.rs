@@ -0,0 +1,133 @@
+use anyhow::anyhow;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::cache;
+
+/// Result of the task of a host and how long it ran
+pub struct HostResult<T> {
+    pub host: String,
+    pub result: anyhow::Result<T>,
+    pub duration: Duration,
+}
+
+/// Why a host did not finish its task
+#[derive(Debug)]
+pub enum Interrupted {
+    Timeout(Duration),
+    Cancelled,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout(timeout) => {
+                write!(f, "timed out after {}", cache::format_duration(*timeout))
+            }
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Runs a task for each host with at most `forks` hosts in flight
+pub struct Runner {
+    forks: usize,
+    timeout: Option<Duration>,
+}
+
+impl Runner {
+    /// A zero `timeout` disables the per-host timeout
+    pub fn new(forks: usize, timeout: Duration) -> Self {
+        Self {
+            forks: forks.max(1),
+            timeout: (!timeout.is_zero()).then_some(timeout),
+        }
+    }
+
+    /// Run `task` on every host, calling `report` as each host finishes. Results
+    /// are returned in host order, hosts still running or pending when Ctrl-C is
+    /// pressed are cancelled.
+    pub async fn run<T, F, Fut>(
+        &self,
+        hosts: &[String],
+        task: F,
+        mut report: impl FnMut(&HostResult<T>),
+    ) -> Vec<HostResult<T>>
+    where
+        T: Send + 'static,
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>> + Send + 'static,
+    {
+        let mut results = hosts.iter().map(|_| None).collect::<Vec<_>>();
+        let mut pending = hosts.iter().enumerate();
+        let mut tasks = JoinSet::new();
+        let mut indexes = HashMap::new();
+        let mut started = hosts.iter().map(|_| None).collect::<Vec<Option<Instant>>>();
+        let interrupt = tokio::signal::ctrl_c();
+        tokio::pin!(interrupt);
+        loop {
+            while tasks.len() < self.forks {
+                let Some((index, host)) = pending.next() else {
+                    break;
+                };
+                debug!("starting task for {host}");
+                started[index] = Some(Instant::now());
+                let future = task(host.clone());
+                let timeout = self.timeout;
+                let handle = tasks.spawn(async move {
+                    match timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, future)
+                            .await
+                            .unwrap_or_else(|_| Err(Interrupted::Timeout(timeout).into())),
+                        None => future.await,
+                    }
+                });
+                indexes.insert(handle.id(), index);
+            }
+            tokio::select! {
+                joined = tasks.join_next_with_id() => {
+                    let (index, result) = match joined {
+                        None => break,
+                        Some(Ok((id, result))) => (indexes[&id], result),
+                        Some(Err(error)) => (indexes[&error.id()], Err(anyhow!("task failed: {error}"))),
+                    };
+                    let result = HostResult {
+                        host: hosts[index].clone(),
+                        result,
+                        duration: started[index].map(|x| x.elapsed()).unwrap_or_default(),
+                    };
+                    report(&result);
+                    results[index] = Some(result);
+                }
+                _ = &mut interrupt => {
+                    warn!("interrupted, cancelling {} running hosts", tasks.len());
+                    tasks.abort_all();
+                    // cancelled hosts are dropped, and run their cleanup, before returning
+                    while tasks.join_next().await.is_some() {}
+                    break;
+                }
+            }
+        }
+        hosts
+            .iter()
+            .zip(results)
+            .zip(started)
+            .map(|((host, result), started)| {
+                result.unwrap_or_else(|| {
+                    let result = HostResult {
+                        host: host.clone(),
+                        result: Err(Interrupted::Cancelled.into()),
+                        duration: started.map(|x| x.elapsed()).unwrap_or_default(),
+                    };
+                    report(&result);
+                    result
+                })
+            })
+            .collect()
+    }
+}
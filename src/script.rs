@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Ok};
+use log::{debug, warn};
+use russh::ChannelMsg;
+use russh_sftp::protocol::{FileAttributes, OpenFlags};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+use crate::ssh::{quote, Session, SharedHandle};
+
+/// Removals of scripts left by cancelled or timed out hosts
+static REMOVALS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Local script run by `exec -x`
+#[derive(Debug)]
+pub struct Script {
+    name: String,
+    data: Vec<u8>,
+    /// Program running the script, from `--interpreter` or the shebang line
+    interpreter: Option<String>,
+    /// Quoted script arguments
+    arguments: String,
+}
+
+impl Script {
+    pub fn load(path: &Path, interpreter: Option<String>, args: &[String]) -> anyhow::Result<Self> {
+        let data =
+            std::fs::read(path).map_err(|error| anyhow!("unable to read {path:?}: {error}"))?;
+        let interpreter = interpreter.or_else(|| shebang(&data));
+        debug!("script interpreter: {interpreter:?}");
+        Ok(Self {
+            name: path
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or("script".to_string()),
+            data,
+            interpreter,
+            arguments: args.iter().map(|x| quote(x)).collect::<Vec<_>>().join(" "),
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Command running the script read from stdin
+    pub fn piped(&self) -> String {
+        match &self.interpreter {
+            Some(interpreter) => format!("{interpreter} /dev/stdin {}", self.arguments),
+            None => format!("sh -s -- {}", self.arguments),
+        }
+    }
+
+    /// Command running the script uploaded to `path`, which removes it once the
+    /// script exits even if the connection is lost
+    pub fn uploaded(&self, path: &str) -> String {
+        let path = quote(path);
+        let run = format!(
+            "{} {path} {}",
+            self.interpreter.as_deref().unwrap_or("sh"),
+            self.arguments
+        );
+        let cleanup = quote(&format!("rm -f -- {path}"));
+        format!(
+            "sh -c {}",
+            quote(&format!(
+                "trap {cleanup} EXIT; trap exit HUP INT TERM; {run}"
+            ))
+        )
+    }
+
+    /// Upload the script to a new temporary path, readable by everyone when
+    /// `shared` so other users can run it
+    pub async fn upload(&self, session: &Session, shared: bool) -> anyhow::Result<Uploaded> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let path = format!("/tmp/.asd-{:x}{nanos:x}-{}", std::process::id(), self.name);
+        debug!("uploading script to {path}");
+        let sftp = session.sftp().await?;
+        let flags = OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::EXCLUDE;
+        let mut file = sftp.open_with_flags(&path, flags).await?;
+        // from here on a failed upload leaves a file behind that the guard removes
+        let uploaded = Uploaded {
+            handle: session.handle(),
+            path,
+            removed: false,
+        };
+        file.write_all(&self.data).await?;
+        file.shutdown().await?;
+        let attributes = FileAttributes {
+            permissions: Some(if shared { 0o755 } else { 0o700 }),
+            ..FileAttributes::empty()
+        };
+        sftp.set_metadata(&uploaded.path, attributes).await?;
+        sftp.close().await?;
+        Ok(uploaded)
+    }
+}
+
+/// Script uploaded to a remote. It is removed when dropped before `remove`, so
+/// it is not left behind by failed, cancelled or timed out hosts.
+pub struct Uploaded {
+    handle: SharedHandle,
+    path: String,
+    removed: bool,
+}
+
+impl Uploaded {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Remove the uploaded script if the command did not, e.g. when it never
+    /// started or ran as a user unable to delete it
+    pub async fn remove(mut self) {
+        self.removed = true;
+        remove(&self.handle, &self.path).await;
+    }
+}
+
+impl Drop for Uploaded {
+    fn drop(&mut self) {
+        if self.removed {
+            return;
+        }
+        // the handle clone keeps the connection open until the script is removed
+        let (handle, path) = (self.handle.clone(), self.path.clone());
+        let task = tokio::spawn(async move { remove(&handle, &path).await });
+        REMOVALS.lock().unwrap().push(task);
+    }
+}
+
+/// Wait for the scripts of cancelled hosts to be removed, giving up after a
+/// few seconds so an unresponsive remote does not hold the exit
+pub async fn wait_removals() {
+    let tasks = std::mem::take(&mut *REMOVALS.lock().unwrap());
+    if tasks.is_empty() {
+        return;
+    }
+    debug!("waiting for {} script removals", tasks.len());
+    let wait = async {
+        for task in tasks {
+            let _ = task.await;
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .is_err()
+    {
+        warn!("timed out removing uploaded scripts");
+    }
+}
+
+async fn remove(handle: &SharedHandle, path: &str) {
+    let command = format!("rm -f -- {}", quote(path));
+    let result = async {
+        let mut channel = handle.read().await.channel_open_session().await?;
+        channel.exec(true, command).await?;
+        let mut code = None;
+        while let Some(msg) = channel.wait().await {
+            if let ChannelMsg::ExitStatus { exit_status } = msg {
+                code = Some(exit_status);
+            }
+        }
+        Ok(code)
+    };
+    match result.await {
+        Result::Ok(Some(0)) => debug!("removed script {path}"),
+        Result::Ok(code) => warn!("unable to remove script {path}, exit code {code:?}"),
+        Err(error) => warn!("unable to remove script {path}: {error}"),
+    }
+}
+
+/// Interpreter in the shebang line of a script
+fn shebang(data: &[u8]) -> Option<String> {
+    let line = data.strip_prefix(b"#!")?.split(|x| *x == b'\n').next()?;
+    let interpreter = String::from_utf8_lossy(line).trim().to_string();
+    (!interpreter.is_empty()).then_some(interpreter)
+}
@@ -1,16 +1,41 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::env;
-use std::sync::Arc;
+use std::io::{Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
-use russh::keys::key;
-use russh::{client, ChannelMsg, Disconnect};
+use clap::ValueEnum;
+use log::{debug, warn};
+use russh::keys::{key, load_secret_key};
+use russh::{client, Channel, ChannelMsg, Disconnect};
+use russh_sftp::client::SftpSession;
+use serde::{Deserialize, Serialize};
+use termion::raw::{IntoRawMode, RawTerminal};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::ToSocketAddrs;
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 
-pub struct Client {}
+use crate::environment;
+use crate::escape::{self, Command, Escape, Escapes, Input};
+use crate::forward::{self, Forward};
+use crate::recording::Recording;
+
+/// Connection handle shared with forward listeners and master connection clients,
+/// requesting remote forwards takes it exclusively
+pub type SharedHandle = Arc<RwLock<client::Handle<Client>>>;
+
+/// Local destinations of remote forwards, indexed by the port bound in the remote
+type RemoteForwards = Arc<Mutex<HashMap<u32, (String, u16)>>>;
+
+#[derive(Default)]
+pub struct Client {
+    remote_forwards: RemoteForwards,
+}
 
 #[async_trait]
 impl client::Handler for Client {
@@ -22,38 +47,462 @@ impl client::Handler for Client {
     ) -> Result<bool, Self::Error> {
         Ok(true)
     }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<client::Msg>,
+        connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        let target = self
+            .remote_forwards
+            .lock()
+            .unwrap()
+            .get(&connected_port)
+            .cloned();
+        let Some((host, port)) = target else {
+            warn!("unexpected forwarded connection to {connected_address}:{connected_port}");
+            return Ok(());
+        };
+        debug!(
+            "forwarding connection from {originator_address}:{originator_port} to {host}:{port}"
+        );
+        tokio::spawn(async move {
+            match TcpStream::connect((host.as_str(), port)).await {
+                Ok(stream) => forward::tunnel(stream, channel.into_stream()).await,
+                Err(error) => warn!("unable to connect to {host}:{port}: {error}"),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Credentials and address used to log into a remote
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Login {
+    pub user: String,
+    pub address: String,
+    pub port: u16,
+    pub password: String,
+    pub identity_files: Vec<PathBuf>,
+    /// Where the password came from, only kept for the audit log
+    #[serde(skip)]
+    pub source: Option<PasswordSource>,
+}
+
+/// Collected output of a command, `code` is `None` if the remote closed the
+/// channel without an exit status
+#[derive(Debug, Default)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub code: Option<u32>,
+}
+
+/// How a session authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    PublicKey,
+    Password,
+    /// Reused the master connection of the remote
+    Master,
+}
+
+/// Where the password of a login came from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordSource {
+    /// Password cache of a previous login
+    Cached,
+    /// Stored credential matching the remote
+    Detected,
+    /// Typed at the prompt
+    Asked,
+}
+
+/// Errors connecting to a remote, told apart so callers can report them
+#[derive(Debug)]
+pub enum ConnectError {
+    Unreachable(String),
+    AuthFailed,
+    /// The connection was lost after the session started
+    Disconnected,
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreachable(error) => write!(f, "{error}"),
+            Self::AuthFailed => write!(f, "Authentication failed"),
+            Self::Disconnected => write!(f, "Connection lost"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// Terminal multiplexer holding the interactive shell, so its programs keep
+/// running if the connection is lost
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Multiplexer {
+    Tmux,
+    Screen,
+}
+
+impl Multiplexer {
+    /// Command attaching to the `asd` session, created if missing. Other clients
+    /// are detached, such as the one of a lost connection.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Tmux => "tmux new-session -A -D -s asd",
+            Self::Screen => "screen -D -R -S asd",
+        }
+    }
+}
+
+/// Keepalive requests sent on idle connections, from `ServerAliveInterval` and
+/// `ServerAliveCountMax`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Keepalive {
+    /// `None` disables keepalives
+    pub interval: Option<Duration>,
+    /// Unanswered requests before the connection is considered lost
+    pub count_max: usize,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(15)),
+            count_max: 3,
+        }
+    }
+}
+
+static KEEPALIVE: OnceLock<Keepalive> = OnceLock::new();
+
+/// Set the keepalive of every connection opened afterwards, only the first call
+/// has any effect
+pub fn set_keepalive(keepalive: Keepalive) {
+    if KEEPALIVE.set(keepalive).is_ok() {
+        debug!("keepalive: {keepalive:?}");
+    }
+}
+
+pub fn keepalive() -> Keepalive {
+    KEEPALIVE.get().copied().unwrap_or_default()
 }
 
 pub struct Session {
-    session: client::Handle<Client>,
+    session: SharedHandle,
+    pub auth: AuthMethod,
+    remote_forwards: RemoteForwards,
+    forwards: Vec<JoinHandle<()>>,
+    // jump host the connection is tunneled through, kept alive with the session
+    jump: Option<Box<Session>>,
+    /// Variables sent to the remote on every command
+    env: Vec<(String, String)>,
+    /// Where the output of interactive sessions is recorded
+    recording: Option<Recording>,
+    /// Forwards started by `forward`, listed by the `~#` escape
+    forwarded: Vec<Forward>,
 }
 
 impl Session {
-    pub async fn connect<A: ToSocketAddrs>(
+    pub async fn connect(
         user: impl Into<String>,
         password: impl Into<String>,
-        addrs: A,
+        identity_files: &[PathBuf],
+        (host, port): (&str, u16),
+        jump: Option<Session>,
     ) -> Result<Self> {
-        let config = client::Config {
-            inactivity_timeout: Some(Duration::from_secs(5)),
-            ..<_>::default()
-        };
+        let user = user.into();
 
-        let config = Arc::new(config);
-        let sh = Client {};
-
-        let mut session = client::connect(config, addrs, sh).await?;
+        let client = Client::default();
+        let remote_forwards = client.remote_forwards.clone();
+        let mut session = open_handle((host, port), jump.as_ref(), client)
+            .await
+            .map_err(|error| ConnectError::Unreachable(error.to_string()))?;
+        let jump = jump.map(Box::new);
+        let into_session = |session, auth| Self {
+            session: Arc::new(RwLock::new(session)),
+            auth,
+            remote_forwards,
+            forwards: Vec::new(),
+            jump,
+            env: Vec::new(),
+            recording: None,
+            forwarded: Vec::new(),
+        };
+        for file in identity_files.iter().filter(|x| x.exists()) {
+            // keys protected with a passphrase are skipped in favor of password auth
+            let Ok(key) = load_secret_key(file, None) else {
+                debug!("unable to load identity file {file:?}");
+                continue;
+            };
+            if session.authenticate_publickey(&user, Arc::new(key)).await? {
+                debug!("authenticated with identity file {file:?}");
+                return Ok(into_session(session, AuthMethod::PublicKey));
+            }
+        }
         let auth_res = session.authenticate_password(user, password).await?;
 
         if !auth_res {
-            anyhow::bail!("Authentication failed");
+            anyhow::bail!(ConnectError::AuthFailed);
         }
 
-        Ok(Self { session })
+        Ok(into_session(session, AuthMethod::Password))
     }
 
+    /// Open a session through the control socket of a master connection,
+    /// `None` if no master is listening on it
+    pub async fn connect_control(socket: &Path, user: &str) -> Result<Option<Self>> {
+        if !socket.exists() {
+            return Ok(None);
+        }
+        let stream = match UnixStream::connect(socket).await {
+            Ok(stream) => stream,
+            Err(error) => {
+                debug!("removing stale control socket {socket:?}: {error}");
+                std::fs::remove_file(socket)?;
+                return Ok(None);
+            }
+        };
+        let client = Client::default();
+        let remote_forwards = client.remote_forwards.clone();
+        let mut session = client::connect_stream(client_config(), stream, client).await?;
+        if !session.authenticate_none(user).await? {
+            anyhow::bail!("master connection refused the session");
+        }
+        debug!("reusing master connection {socket:?}");
+        Ok(Some(Self {
+            session: Arc::new(RwLock::new(session)),
+            auth: AuthMethod::Master,
+            remote_forwards,
+            forwards: Vec::new(),
+            jump: None,
+            env: Vec::new(),
+            recording: None,
+            forwarded: Vec::new(),
+        }))
+    }
+
+    /// Set the variables sent to the remote on every command
+    pub fn set_env(&mut self, env: Vec<(String, String)>) {
+        self.env = env;
+    }
+
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    /// Record the output of the interactive commands
+    pub fn record(&mut self, recording: Recording) {
+        self.recording = Some(recording);
+    }
+
+    /// Request the environment on a channel before running its command, returns
+    /// an `export` statement for the variables the remote rejected
+    async fn send_env(&self, channel: &mut Channel<client::Msg>) -> Result<String> {
+        let mut rejected = Vec::new();
+        for (key, value) in &self.env {
+            channel.set_env(true, key.as_str(), value.as_str()).await?;
+            loop {
+                match channel.wait().await {
+                    Some(ChannelMsg::Success) => break,
+                    Some(ChannelMsg::Failure) => {
+                        rejected.push((key.clone(), value.clone()));
+                        break;
+                    }
+                    Some(_) => {}
+                    None => anyhow::bail!("channel closed while sending the environment"),
+                }
+            }
+        }
+        if !rejected.is_empty() {
+            debug!(
+                "remote rejected {} environment variables, exporting them instead",
+                rejected.len()
+            );
+        }
+        Ok(environment::exports(&rejected))
+    }
+
+    /// Connection handle shared with the master connection clients
+    pub fn handle(&self) -> SharedHandle {
+        self.session.clone()
+    }
+
+    /// Try each password in order, returning the index of the first one accepted
+    /// by the remote. Attempts share a connection until the server drops it.
+    /// The host key is not verified, so every password tried is exposed to the
+    /// remote, callers limit them to the credentials scoped to it.
+    pub async fn detect_password(
+        user: &str,
+        passwords: &[String],
+        (host, port): (&str, u16),
+        jump: Option<&Session>,
+    ) -> Result<Option<usize>> {
+        let mut session: Option<client::Handle<Client>> = None;
+        for (index, password) in passwords.iter().enumerate() {
+            for retry in [false, true] {
+                let handle = match session.as_mut() {
+                    Some(handle) if !handle.is_closed() => handle,
+                    _ => session.insert(
+                        open_handle((host, port), jump, Client::default())
+                            .await
+                            .map_err(|error| ConnectError::Unreachable(error.to_string()))?,
+                    ),
+                };
+                match handle.authenticate_password(user, password).await {
+                    Ok(true) => {
+                        debug!("password {index} accepted");
+                        handle
+                            .disconnect(Disconnect::ByApplication, "", "English")
+                            .await?;
+                        return Ok(Some(index));
+                    }
+                    Ok(false) => {
+                        debug!("password {index} rejected");
+                        break;
+                    }
+                    Err(error) if !retry => {
+                        debug!("connection lost while testing password {index}: {error}");
+                        session = None;
+                    }
+                    Err(error) => return Err(ConnectError::Unreachable(error.to_string()).into()),
+                }
+            }
+        }
+        if let Some(handle) = session {
+            handle
+                .disconnect(Disconnect::ByApplication, "", "English")
+                .await?;
+        }
+        Ok(None)
+    }
+
+    /// Start the requested port forwards, they run until the session is closed
+    pub async fn forward(&mut self, forwards: &[Forward]) -> Result<()> {
+        for forward in forwards {
+            let active = match forward {
+                Forward::Local {
+                    bind,
+                    port,
+                    host,
+                    host_port,
+                } => {
+                    let listener = TcpListener::bind((bind.as_str(), *port)).await?;
+                    debug!("forwarding {bind}:{port} to remote {host}:{host_port}");
+                    self.forwards.push(tokio::spawn(forward::listen_local(
+                        self.session.clone(),
+                        listener,
+                        host.clone(),
+                        *host_port,
+                    )));
+                    forward.clone()
+                }
+                Forward::Dynamic { bind, port } => {
+                    let listener = TcpListener::bind((bind.as_str(), *port)).await?;
+                    debug!("listening for socks connections on {bind}:{port}");
+                    self.forwards.push(tokio::spawn(forward::listen_dynamic(
+                        self.session.clone(),
+                        listener,
+                    )));
+                    forward.clone()
+                }
+                Forward::Remote {
+                    bind,
+                    port,
+                    host,
+                    host_port,
+                } => {
+                    let bound = self
+                        .session
+                        .write()
+                        .await
+                        .tcpip_forward(bind, *port as u32)
+                        .await?;
+                    let bound = if *port == 0 { bound } else { *port as u32 };
+                    if *port == 0 {
+                        eprintln!(
+                            "Allocated port {bound} for remote forward to {host}:{host_port}"
+                        );
+                    }
+                    debug!("forwarding remote {bind}:{bound} to {host}:{host_port}");
+                    self.remote_forwards
+                        .lock()
+                        .unwrap()
+                        .insert(bound, (host.clone(), *host_port));
+                    Forward::Remote {
+                        bind: bind.clone(),
+                        port: bound as u16,
+                        host: host.clone(),
+                        host_port: *host_port,
+                    }
+                }
+            };
+            self.forwarded.push(active);
+        }
+        Ok(())
+    }
+
+    /// Wait until the remote closes the connection
+    pub async fn wait_closed(&self) {
+        while !self.session.read().await.is_closed() {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Run a command without a PTY, sending `stdin` if given. Output is passed to
+    /// `output` as it arrives, flagged as stderr or not. Returns the exit code,
+    /// `None` if the remote closed the channel without one.
+    pub async fn exec(
+        &self,
+        command: &str,
+        stdin: Option<&[u8]>,
+        mut output: impl FnMut(bool, &[u8]) -> std::io::Result<()>,
+    ) -> Result<Option<u32>> {
+        let mut channel = self.session.read().await.channel_open_session().await?;
+        let exports = self.send_env(&mut channel).await?;
+        channel.exec(true, format!("{exports}{command}")).await?;
+        if let Some(stdin) = stdin {
+            channel.data(stdin).await?;
+        }
+        channel.eof().await?;
+        let mut code = None;
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { ref data } => output(false, data)?,
+                ChannelMsg::ExtendedData { ref data, ext: 1 } => output(true, data)?,
+                ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
+                ChannelMsg::ExitSignal { signal_name, .. } => {
+                    anyhow::bail!("command killed by signal {signal_name:?}")
+                }
+                ChannelMsg::Failure => anyhow::bail!("remote refused to run the command"),
+                ChannelMsg::Close => break,
+                _ => {}
+            }
+        }
+        Ok(code)
+    }
+
+    /// Open a SFTP session on a new channel
+    pub async fn sftp(&self) -> Result<SftpSession> {
+        let channel = self.session.read().await.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        Ok(SftpSession::new(channel.into_stream()).await?)
+    }
+
+    /// Run a command in a PTY attached to the terminal, which is put in raw mode
+    /// until the command exits. Escape sequences typed after a newline are
+    /// handled locally, disconnecting with `~.` returns 255.
     pub async fn call(&mut self, command: &str) -> Result<u32> {
-        let mut channel = self.session.channel_open_session().await?;
+        let mut channel = self.session.read().await.channel_open_session().await?;
 
         let (w, h) = termion::terminal_size()?;
 
@@ -69,15 +518,18 @@ impl Session {
                 &[], // ideally you want to pass the actual terminal modes here
             )
             .await?;
-        channel.exec(true, command).await?;
+        let exports = self.send_env(&mut channel).await?;
+        channel.exec(true, format!("{exports}{command}")).await?;
 
         let code;
+        let mut raw = std::io::stdout().into_raw_mode()?;
         let mut stdin = tokio_fd::AsyncFd::try_from(0)?;
         let mut stdout = tokio_fd::AsyncFd::try_from(1)?;
         let mut buf = vec![0; 1024];
         let mut stdin_closed = false;
+        let mut escapes = Escapes::default();
 
-        loop {
+        'session: loop {
             // Handle one of the possible events:
             tokio::select! {
                 // There's terminal input available from the user
@@ -87,18 +539,41 @@ impl Session {
                             stdin_closed = true;
                             channel.eof().await?;
                         },
-                        // Send it to the server
-                        Ok(n) => channel.data(&buf[..n]).await?,
+                        // Send it to the server, minus the escape sequences
+                        Ok(n) => for input in escapes.feed(&buf[..n]) {
+                            match input {
+                                Input::Data(data) => channel
+                                    .data(&data[..])
+                                    .await
+                                    .map_err(|_| ConnectError::Disconnected)?,
+                                Input::Escape(escape) => {
+                                    if self.escape(escape, &channel, &mut raw, &mut stdin).await? {
+                                        code = 255;
+                                        break 'session;
+                                    }
+                                }
+                            }
+                        },
                         Err(e) => return Err(e.into()),
                     };
                 },
                 // There's an event available on the session channel
-                Some(msg) = channel.wait() => {
+                msg = channel.wait() => {
+                    // the channel only ends without an exit status if the connection is lost
+                    let Some(msg) = msg else {
+                        return Err(ConnectError::Disconnected.into());
+                    };
                     match msg {
                         // Write data to the terminal
                         ChannelMsg::Data { ref data } => {
                             stdout.write_all(data).await?;
                             stdout.flush().await?;
+                            if let Some(recording) = self.recording.as_mut() {
+                                if let Err(error) = recording.output(data) {
+                                    warn!("unable to record to {:?}, recording stopped: {error}", recording.path());
+                                    self.recording = None;
+                                }
+                            }
                         }
                         // The command has returned an exit code
                         ChannelMsg::ExitStatus { exit_status } => {
@@ -116,10 +591,132 @@ impl Session {
         Ok(code)
     }
 
+    /// Handle an escape sequence of `call`, returns whether to disconnect
+    async fn escape(
+        &mut self,
+        escape: Escape,
+        channel: &Channel<client::Msg>,
+        raw: &mut RawTerminal<Stdout>,
+        stdin: &mut tokio_fd::AsyncFd,
+    ) -> Result<bool> {
+        debug!("escape sequence {escape:?}");
+        match escape {
+            Escape::Disconnect => {
+                print_raw("\nConnection closed.\n")?;
+                channel.close().await?;
+                return Ok(true);
+            }
+            Escape::Help => print_raw(&format!("\n{}", escape::HELP))?,
+            Escape::List if self.forwarded.is_empty() => print_raw("\nNo forwarded ports.\n")?,
+            Escape::List => {
+                let forwards = self
+                    .forwarded
+                    .iter()
+                    .map(|x| format!("  {x}\n"))
+                    .collect::<String>();
+                print_raw(&format!("\nThe following forwards are active:\n{forwards}"))?
+            }
+            Escape::Suspend => {
+                print_raw("\n[suspend asd]\n")?;
+                raw.suspend_raw_mode()?;
+                // stops until the shell resumes the process
+                unsafe { libc::raise(libc::SIGTSTP) };
+                raw.activate_raw_mode()?;
+                let (w, h) = termion::terminal_size()?;
+                channel.window_change(w as u32, h as u32, 0, 0).await?;
+            }
+            Escape::Command => {
+                // the command line is read and run in cooked mode, for line editing
+                raw.suspend_raw_mode()?;
+                print!("\nssh> ");
+                std::io::stdout().flush()?;
+                match escape::parse_command(&read_line(stdin).await?) {
+                    Ok(Some(Command::Forward(forward))) => {
+                        match self.forward(std::slice::from_ref(&forward)).await {
+                            Ok(()) => println!("Forwarding port."),
+                            Err(error) => println!("{error}"),
+                        }
+                    }
+                    Ok(Some(Command::Help)) => print!("{}", escape::COMMAND_HELP),
+                    Ok(None) => {}
+                    Err(error) => print!("{error}\n{}", escape::COMMAND_HELP),
+                }
+                raw.activate_raw_mode()?;
+            }
+        }
+        Ok(false)
+    }
+
     pub async fn close(&mut self) -> Result<()> {
-        self.session
-            .disconnect(Disconnect::ByApplication, "", "English")
-            .await?;
+        for task in self.forwards.drain(..) {
+            task.abort();
+        }
+        let session = self.session.read().await;
+        if !session.is_closed() {
+            session
+                .disconnect(Disconnect::ByApplication, "", "English")
+                .await?;
+        }
+        if let Some(jump) = self.jump.as_mut() {
+            Box::pin(jump.close()).await?;
+        }
         Ok(())
     }
 }
+
+/// Print to a terminal in raw mode, which needs carriage returns
+fn print_raw(text: &str) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(text.replace('\n', "\r\n").as_bytes())?;
+    stdout.flush()
+}
+
+/// Read a line typed in cooked mode, without the newline
+async fn read_line(stdin: &mut tokio_fd::AsyncFd) -> Result<String> {
+    let mut line = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        let n = stdin.read(&mut buf).await?;
+        line.extend_from_slice(&buf[..n]);
+        if n == 0 || line.ends_with(b"\n") {
+            return Ok(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+    }
+}
+
+/// Quote an argument for a POSIX shell
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn client_config() -> Arc<client::Config> {
+    let keepalive = keepalive();
+    // idle sessions stay open, lost connections are told by unanswered keepalives
+    Arc::new(client::Config {
+        keepalive_interval: keepalive.interval,
+        keepalive_max: keepalive.count_max,
+        ..<_>::default()
+    })
+}
+
+/// Open a SSH transport to the remote, tunneled through a `direct-tcpip`
+/// channel of the jump host session if given
+async fn open_handle(
+    (host, port): (&str, u16),
+    jump: Option<&Session>,
+    client: Client,
+) -> Result<client::Handle<Client>> {
+    match jump {
+        Some(jump) => {
+            debug!("opening tunnel to {host}:{port} through jump host");
+            let channel = jump
+                .session
+                .read()
+                .await
+                .channel_open_direct_tcpip(host, port as u32, "127.0.0.1", 0)
+                .await?;
+            Ok(client::connect_stream(client_config(), channel.into_stream(), client).await?)
+        }
+        None => Ok(client::connect(client_config(), (host, port), client).await?),
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{anyhow, Ok};
+use log::{debug, trace, warn};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// Connection options resolved from OpenSSH client configuration files
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+    pub proxy_jump: Option<String>,
+    /// Patterns of the local variables to send, e.g. `LC_*`
+    pub send_env: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Criteria {
+    All,
+    Host(Vec<String>),
+    Match(Vec<(bool, String, Vec<String>)>),
+}
+
+#[derive(Debug)]
+struct Block {
+    criteria: Criteria,
+    options: Vec<(String, String)>,
+}
+
+/// Parsed `ssh_config` files, evaluated in order with the first obtained value winning
+#[derive(Debug, Default)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+    home: PathBuf,
+}
+
+impl SshConfig {
+    /// Load configuration files in order, missing files are skipped
+    pub fn load(files: &[String], home: &Path) -> Self {
+        let mut config = Self {
+            blocks: Vec::new(),
+            home: home.to_path_buf(),
+        };
+        for file in files {
+            let path = config.expand_path(file);
+            if !path.exists() {
+                trace!("ssh config {path:?} not found");
+                continue;
+            }
+            // relative includes go from the directory of the top level file, like
+            // ~/.ssh for the user config and /etc/ssh for the system one
+            let base = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+            if let Err(error) = config.parse_file(&path, &base, Criteria::All, 0) {
+                warn!("unable to read ssh config {path:?}: {error}");
+            }
+        }
+        trace!("ssh config: {config:?}");
+        config
+    }
+
+    fn parse_file(
+        &mut self,
+        path: &Path,
+        base: &Path,
+        criteria: Criteria,
+        depth: usize,
+    ) -> anyhow::Result<()> {
+        if depth > 16 {
+            return Err(anyhow!("too many nested includes at {path:?}"));
+        }
+        debug!("reading ssh config: {path:?}");
+        let data = fs::read_to_string(path)?;
+        self.blocks.push(Block {
+            criteria,
+            options: Vec::new(),
+        });
+        for line in data.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = split_directive(line);
+            let keyword = keyword.to_lowercase();
+            match keyword.as_str() {
+                "host" => self.blocks.push(Block {
+                    criteria: Criteria::Host(split_values(&value)),
+                    options: Vec::new(),
+                }),
+                "match" => self.blocks.push(Block {
+                    criteria: parse_match(&value),
+                    options: Vec::new(),
+                }),
+                "include" => {
+                    let criteria = self.blocks.last().unwrap().criteria.clone();
+                    for pattern in split_values(&value) {
+                        let pattern = self.expand_path(&pattern);
+                        let pattern = if pattern.is_relative() {
+                            base.join(pattern)
+                        } else {
+                            pattern
+                        };
+                        let mut files = glob::glob(&pattern.to_string_lossy())?
+                            .filter_map(|x| x.ok())
+                            .collect::<Vec<PathBuf>>();
+                        files.sort();
+                        for file in files {
+                            self.parse_file(&file, base, criteria.clone(), depth + 1)?;
+                        }
+                    }
+                    // directives after the include still belong to the enclosing block
+                    self.blocks.push(Block {
+                        criteria,
+                        options: Vec::new(),
+                    });
+                }
+                _ => self
+                    .blocks
+                    .last_mut()
+                    .unwrap()
+                    .options
+                    .push((keyword, value)),
+            }
+        }
+        Ok(())
+    }
+
+    fn expand_path(&self, path: &str) -> PathBuf {
+        match path.strip_prefix("~/") {
+            Some(path) => self.home.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Resolve options for `alias` like `ssh alias` would, `user` is the login
+    /// user given in the command line if any
+    pub fn resolve(&self, alias: &str, user: Option<&str>) -> HostConfig {
+        let mut host = HostConfig::default();
+        for block in &self.blocks {
+            let hostname = host.hostname.clone().unwrap_or(alias.to_string());
+            let user = user.map(str::to_string).or(host.user.clone());
+            let matches = match &block.criteria {
+                Criteria::All => true,
+                Criteria::Host(patterns) => match_patterns(patterns, alias),
+                Criteria::Match(conditions) => conditions.iter().all(|(negate, name, values)| {
+                    let result = match name.as_str() {
+                        "all" => true,
+                        "host" => values
+                            .iter()
+                            .any(|x| match_patterns(&split_list(x), &hostname)),
+                        "originalhost" => {
+                            values.iter().any(|x| match_patterns(&split_list(x), alias))
+                        }
+                        "user" => user.as_ref().is_some_and(|user| {
+                            values.iter().any(|x| match_patterns(&split_list(x), user))
+                        }),
+                        "localuser" => env::var("USER").is_ok_and(|user| {
+                            values.iter().any(|x| match_patterns(&split_list(x), &user))
+                        }),
+                        name => {
+                            debug!("unsupported ssh config match criteria: {name}");
+                            false
+                        }
+                    };
+                    result != *negate
+                }),
+            };
+            if !matches {
+                continue;
+            }
+            for (keyword, value) in &block.options {
+                match keyword.as_str() {
+                    "hostname" if host.hostname.is_none() => {
+                        host.hostname = Some(value.replace("%h", alias).replace("%%", "%"))
+                    }
+                    "user" if host.user.is_none() => host.user = Some(value.clone()),
+                    "port" if host.port.is_none() => match value.parse() {
+                        Result::Ok(port) => host.port = Some(port),
+                        Err(_) => warn!("invalid port {value:?} in ssh config"),
+                    },
+                    "identityfile" => {
+                        let path = value
+                            .replace("%d", &self.home.to_string_lossy())
+                            .replace("%h", &hostname)
+                            .replace("%r", user.as_deref().unwrap_or_default())
+                            .replace("%%", "%");
+                        host.identity_files.push(self.expand_path(&path));
+                    }
+                    "proxyjump" if host.proxy_jump.is_none() => {
+                        host.proxy_jump = Some(value.clone())
+                    }
+                    "sendenv" => host.send_env.extend(split_values(value)),
+                    _ => {}
+                }
+            }
+        }
+        trace!("resolved ssh config for {alias}: {host:?}");
+        host
+    }
+}
+
+fn split_directive(line: &str) -> (String, String) {
+    let split = line
+        .find(|x: char| x.is_whitespace() || x == '=')
+        .unwrap_or(line.len());
+    let (keyword, value) = line.split_at(split);
+    let value = value.trim_start();
+    let value = value.strip_prefix('=').unwrap_or(value).trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .unwrap_or(value);
+    (keyword.to_string(), value.to_string())
+}
+
+fn split_values(value: &str) -> Vec<String> {
+    value.split_whitespace().map(str::to_string).collect()
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::to_string).collect()
+}
+
+fn parse_match(value: &str) -> Criteria {
+    let mut conditions = Vec::new();
+    let mut words = value.split_whitespace();
+    while let Some(word) = words.next() {
+        let (negate, name) = match word.strip_prefix('!') {
+            Some(name) => (true, name.to_lowercase()),
+            None => (false, word.to_lowercase()),
+        };
+        let values = match name.as_str() {
+            "all" | "canonical" | "final" => Vec::new(),
+            _ => words
+                .next()
+                .map(|x| vec![x.to_string()])
+                .unwrap_or_default(),
+        };
+        conditions.push((negate, name, values));
+    }
+    Criteria::Match(conditions)
+}
+
+/// OpenSSH pattern list matching, a negated match always rejects the host
+fn match_patterns(patterns: &[String], host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        let (negate, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        if glob::Pattern::new(pattern).is_ok_and(|x| x.matches_with(host, options)) {
+            if negate {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `files` to a fresh temporary directory and load the first one
+    fn load(name: &str, files: &[(&str, &str)]) -> SshConfig {
+        let dir = env::temp_dir().join(format!("asd-ssh-config-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, data) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        let config = SshConfig::load(&[dir.join(files[0].0).to_string_lossy().into()], &dir);
+        fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let config = load(
+            "first",
+            &[(
+                "config",
+                "Host web*\n  User deploy\n  Port 2222\nHost *\n  User root\n  Port 22\n  HostName %h.example.com\n",
+            )],
+        );
+        let host = config.resolve("web01", None);
+        assert_eq!(host.user.as_deref(), Some("deploy"));
+        assert_eq!(host.port, Some(2222));
+        assert_eq!(host.hostname.as_deref(), Some("web01.example.com"));
+        assert_eq!(config.resolve("db01", None).user.as_deref(), Some("root"));
+    }
+
+    #[test]
+    fn negated_pattern_rejects() {
+        let patterns = vec!["*.example.com".to_string(), "!bastion.*".to_string()];
+        assert!(match_patterns(&patterns, "web.example.com"));
+        assert!(!match_patterns(&patterns, "bastion.example.com"));
+        assert!(!match_patterns(&["!web".to_string()], "db"));
+    }
+
+    #[test]
+    fn match_criteria() {
+        let config = load(
+            "match",
+            &[(
+                "config",
+                "Host web\n  HostName web.internal\nMatch host *.internal\n  Port 2200\n\
+                 Match originalhost web user admin\n  ProxyJump bastion\n\
+                 Match !originalhost web\n  User nobody\n",
+            )],
+        );
+        let host = config.resolve("web", Some("admin"));
+        assert_eq!(host.hostname.as_deref(), Some("web.internal"));
+        assert_eq!(host.port, Some(2200));
+        assert_eq!(host.proxy_jump.as_deref(), Some("bastion"));
+        assert_eq!(host.user, None);
+        let host = config.resolve("web", Some("deploy"));
+        assert_eq!(host.proxy_jump, None);
+        let host = config.resolve("db", None);
+        assert_eq!(host.port, None);
+        assert_eq!(host.user.as_deref(), Some("nobody"));
+    }
+
+    #[test]
+    fn relative_include() {
+        let config = load(
+            "include",
+            &[
+                ("ssh/config", "Include conf.d/*.conf\nHost *\n  User root\n"),
+                ("ssh/conf.d/10-web.conf", "Host web\n  User deploy\n"),
+                ("ssh/conf.d/20-db.conf", "Host db\n  Port 5432\n"),
+            ],
+        );
+        assert_eq!(config.resolve("web", None).user.as_deref(), Some("deploy"));
+        let host = config.resolve("db", None);
+        assert_eq!(host.user.as_deref(), Some("root"));
+        assert_eq!(host.port, Some(5432));
+    }
+
+    #[test]
+    fn directive_separators() {
+        let directive = |keyword: &str, value: &str| (keyword.to_string(), value.to_string());
+        assert_eq!(split_directive("User deploy"), directive("User", "deploy"));
+        assert_eq!(split_directive("Port=2222"), directive("Port", "2222"));
+        assert_eq!(split_directive("Port = 2222"), directive("Port", "2222"));
+        assert_eq!(
+            split_directive("IdentityFile \"~/.ssh/my key\""),
+            directive("IdentityFile", "~/.ssh/my key")
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{anyhow, bail, Ok};
+use std::collections::BTreeMap;
+
+/// Replace every `{{ name }}` in `text` with the value of the variable `name`,
+/// undefined variables are an error
+pub fn render(text: &str, vars: &BTreeMap<String, String>) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let line = text[..text.len() - rest.len() + start]
+            .matches('\n')
+            .count()
+            + 1;
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            bail!(anyhow!("unclosed {{{{ at line {line}"));
+        };
+        let name = rest[start + 2..start + end].trim();
+        match vars.get(name) {
+            Some(value) => rendered.push_str(value),
+            None => bail!(anyhow!("undefined variable {name:?} at line {line}")),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("host".to_string(), "web01".to_string()),
+            ("port".to_string(), "8080".to_string()),
+        ])
+    }
+
+    #[test]
+    fn render_variables() {
+        assert_eq!(
+            render("{{host}}:{{ port }}\n", &vars()).unwrap(),
+            "web01:8080\n"
+        );
+        assert_eq!(render("no variables", &vars()).unwrap(), "no variables");
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let error = render("a\n{{ user }}", &vars()).unwrap_err();
+        assert_eq!(error.to_string(), "undefined variable \"user\" at line 2");
+    }
+
+    #[test]
+    fn unclosed_braces() {
+        let error = render("{{ host }}\n\n{{ port", &vars()).unwrap_err();
+        assert_eq!(error.to_string(), "unclosed {{ at line 3");
+        assert!(render("}} {{", &vars()).is_err());
+    }
+}
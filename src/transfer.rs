@@ -0,0 +1,1160 @@
+use anyhow::{anyhow, bail, Ok};
+use clap::ValueEnum;
+use glob::Pattern;
+use log::{debug, info, warn};
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::{FileAttributes, OpenFlags};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::SeekFrom;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::OnceCell;
+
+use crate::escalation::{self, Become};
+use crate::progress::Tracker;
+use crate::ssh::{quote, Output, Session};
+use crate::template;
+
+/// Files checked by a single remote `sha256sum` call
+const CHECKSUM_BATCH: usize = 100;
+
+/// Suffix of files being transferred, kept on failure so the transfer can resume
+const PART: &str = ".asd-part";
+
+/// Partial file of `path`, named after the size and modification time of the
+/// source so only a part of the same version is resumed
+fn part_path(path: &str, size: u64, mtime: u32) -> String {
+    format!("{path}.{size:x}-{mtime:x}{PART}")
+}
+
+/// Whether the file `name` is a partial file of `target` for any version of the source
+fn is_part_of(name: &str, target: &str) -> bool {
+    name.strip_prefix(target)
+        .and_then(|x| x.strip_prefix('.'))
+        .and_then(|x| x.strip_suffix(PART))
+        .and_then(|x| x.split_once('-'))
+        .is_some_and(|(size, mtime)| {
+            [size, mtime]
+                .iter()
+                .all(|x| !x.is_empty() && x.chars().all(|x| x.is_ascii_hexdigit()))
+        })
+}
+
+/// Remove local partial files of `target` left by transfers of other versions
+/// of the source, which would never be resumed
+async fn remove_stale_parts(target: &Path, part: &Path) -> anyhow::Result<()> {
+    let (Some(dir), Some(name)) = (target.parent(), target.file_name()) else {
+        return Ok(());
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.file_name() != part.file_name()
+            && is_part_of(
+                &entry.file_name().to_string_lossy(),
+                &name.to_string_lossy(),
+            )
+        {
+            debug!("removing stale partial file {path:?}");
+            fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Bytes read at once when copying files
+const COPY_BUFFER: usize = 32 * 1024;
+
+/// How symbolic links are transferred
+#[derive(Debug, Clone, Copy, Default, ValueEnum, PartialEq)]
+pub enum Links {
+    /// Transfer the file or directory the link points to
+    #[default]
+    Follow,
+    /// Recreate the link with the same target
+    Preserve,
+    /// Leave links out
+    Skip,
+}
+
+/// Which files are transferred and how they are compared with the destination
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub recursive: bool,
+    pub skip_unchanged: bool,
+    pub checksum: bool,
+    pub delete: bool,
+    pub verify: bool,
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub links: Links,
+    /// Variables to render uploaded files with, files are sent as is if `None`
+    pub template: Option<BTreeMap<String, String>>,
+    /// Files rendered with `template`, every file if empty
+    pub template_globs: Vec<Pattern>,
+}
+
+impl Options {
+    /// Paths matching an exclude pattern and no include one are left out,
+    /// patterns are matched against the relative path and the file name
+    fn excluded(&self, relative: &str) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        let matches = |x: &Pattern| x.matches(relative) || x.matches(name);
+        self.exclude.iter().any(matches) && !self.include.iter().any(matches)
+    }
+
+    /// Variables to render a file with, matched like excluded paths
+    fn template(&self, relative: &str) -> Option<&BTreeMap<String, String>> {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        let matches = |x: &Pattern| x.matches(relative) || x.matches(name);
+        self.template
+            .as_ref()
+            .filter(|_| self.template_globs.is_empty() || self.template_globs.iter().any(matches))
+    }
+}
+
+/// Counts of a transfer to a single host
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    pub files: u64,
+    pub bytes: u64,
+    pub unchanged: u64,
+    pub deleted: u64,
+    pub resumed: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} files, {} bytes", self.files, self.bytes)?;
+        if self.unchanged > 0 {
+            write!(f, ", {} unchanged", self.unchanged)?;
+        }
+        if self.deleted > 0 {
+            write!(f, ", {} deleted", self.deleted)?;
+        }
+        if self.resumed > 0 {
+            write!(f, ", {} resumed", self.resumed)?;
+        }
+        fmt::Result::Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    File { size: u64, mtime: u32, mode: u32 },
+    Dir { mode: u32 },
+    Link { target: String },
+}
+
+/// Entry of a tree being transferred, `relative` goes from the tree parent
+/// and is separated by `/`
+#[derive(Debug, Clone)]
+struct Entry {
+    relative: String,
+    kind: Kind,
+}
+
+/// Remote path of `name` inside the remote directory `dir`
+fn remote_join(dir: &str, name: &str) -> String {
+    match dir.trim_end_matches('/') {
+        "" if dir.starts_with('/') => format!("/{name}"),
+        "" | "." => name.to_string(),
+        dir => format!("{dir}/{name}"),
+    }
+}
+
+fn file_name(path: &str) -> anyhow::Result<&str> {
+    Path::new(path.trim_end_matches('/'))
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or_else(|| anyhow!("{path:?} has no file name"))
+}
+
+/// Local source named after what it stands for, `.` and `..` have no file name
+async fn local_source(path: &Path) -> anyhow::Result<PathBuf> {
+    match path.file_name() {
+        Some(_) => Ok(path.to_path_buf()),
+        None => Ok(fs::canonicalize(path).await?),
+    }
+}
+
+fn seconds(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+/// Entries of a local file or directory tree, parents before their children
+async fn local_tree(path: &Path, options: &Options) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    // directories already walked, links followed into them would loop forever
+    let mut visited = HashSet::new();
+    let name = file_name(&path.to_string_lossy())?.to_string();
+    let mut pending = vec![(path.to_path_buf(), name)];
+    while let Some((path, relative)) = pending.pop() {
+        if options.excluded(&relative) {
+            debug!("excluding {relative}");
+            continue;
+        }
+        let mut metadata = fs::symlink_metadata(&path).await?;
+        if metadata.is_symlink() {
+            match options.links {
+                Links::Skip => {
+                    debug!("skipping link {path:?}");
+                    continue;
+                }
+                Links::Preserve => {
+                    let target = fs::read_link(&path).await?;
+                    entries.push(Entry {
+                        relative,
+                        kind: Kind::Link {
+                            target: target.to_string_lossy().to_string(),
+                        },
+                    });
+                    continue;
+                }
+                Links::Follow => match fs::metadata(&path).await {
+                    Result::Ok(target) => metadata = target,
+                    Err(error) => {
+                        warn!("skipping broken link {path:?}: {error}");
+                        continue;
+                    }
+                },
+            }
+        }
+        let mode = metadata.permissions().mode() & 0o7777;
+        if metadata.is_dir() {
+            if !options.recursive {
+                bail!(anyhow!("{path:?} is a directory, use --recursive"));
+            }
+            if !visited.insert((metadata.dev(), metadata.ino())) {
+                warn!("skipping {path:?}, already transferred through a link");
+                continue;
+            }
+            entries.push(Entry {
+                relative: relative.clone(),
+                kind: Kind::Dir { mode },
+            });
+            let mut children = Vec::new();
+            let mut dir = fs::read_dir(&path).await?;
+            while let Some(child) = dir.next_entry().await? {
+                let name = child.file_name().to_string_lossy().to_string();
+                children.push((child.path(), format!("{relative}/{name}")));
+            }
+            children.sort();
+            pending.extend(children.into_iter().rev());
+        } else if metadata.is_file() {
+            entries.push(Entry {
+                relative,
+                kind: Kind::File {
+                    size: metadata.len(),
+                    mtime: seconds(metadata.modified()?),
+                    mode,
+                },
+            });
+        } else {
+            warn!("skipping {path:?}, not a regular file");
+        }
+    }
+    Ok(entries)
+}
+
+/// Entries of a remote file or directory tree, parents before their children
+async fn remote_tree(
+    sftp: &SftpSession,
+    path: &str,
+    options: &Options,
+) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    let name = file_name(path)?.to_string();
+    let mut pending = vec![(path.to_string(), name)];
+    while let Some((path, relative)) = pending.pop() {
+        if options.excluded(&relative) {
+            debug!("excluding {relative}");
+            continue;
+        }
+        let mut metadata = sftp.symlink_metadata(&path).await?;
+        if metadata.file_type().is_symlink() {
+            match options.links {
+                Links::Skip => {
+                    debug!("skipping link {path}");
+                    continue;
+                }
+                Links::Preserve => {
+                    let target = sftp.read_link(&path).await?;
+                    entries.push(Entry {
+                        relative,
+                        kind: Kind::Link { target },
+                    });
+                    continue;
+                }
+                Links::Follow => match sftp.metadata(&path).await {
+                    Result::Ok(target) => metadata = target,
+                    Err(error) => {
+                        warn!("skipping broken link {path}: {error}");
+                        continue;
+                    }
+                },
+            }
+        }
+        let mode = metadata.permissions.unwrap_or(0o644) & 0o7777;
+        if metadata.file_type().is_dir() {
+            if !options.recursive {
+                bail!(anyhow!("{path} is a directory, use --recursive"));
+            }
+            if !visited.insert(sftp.canonicalize(&path).await?) {
+                warn!("skipping {path}, already transferred through a link");
+                continue;
+            }
+            entries.push(Entry {
+                relative: relative.clone(),
+                kind: Kind::Dir { mode },
+            });
+            let mut children = sftp
+                .read_dir(&path)
+                .await?
+                .map(|x| {
+                    let name = x.file_name();
+                    (remote_join(&path, &name), format!("{relative}/{name}"))
+                })
+                .collect::<Vec<_>>();
+            children.sort();
+            pending.extend(children.into_iter().rev());
+        } else if metadata.file_type().is_file() {
+            entries.push(Entry {
+                relative,
+                kind: Kind::File {
+                    size: metadata.size.unwrap_or_default(),
+                    mtime: metadata.mtime.unwrap_or_default(),
+                    mode,
+                },
+            });
+        } else {
+            warn!("skipping {path}, not a regular file");
+        }
+    }
+    Ok(entries)
+}
+
+/// SHA-256 of a local file, `None` if it can not be read
+async fn local_checksum(path: &Path) -> Option<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path).ok()?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher).ok()?;
+        Some(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Remote side of a transfer, changes go through the become user if any
+struct Remote<'a> {
+    session: &'a Session,
+    sftp: &'a SftpSession,
+    escalation: Option<&'a Become>,
+    progress: &'a Tracker,
+    /// Private directory of the login user holding files copied by root,
+    /// created on first use
+    staging: OnceCell<String>,
+}
+
+impl Remote<'_> {
+    async fn run(&self, command: &str, stdin: Option<&[u8]>) -> anyhow::Result<Output> {
+        let mut output = Output::default();
+        let collect = |stderr, data: &[u8]| {
+            match stderr {
+                true => output.stderr.extend_from_slice(data),
+                false => output.stdout.extend_from_slice(data),
+            }
+            std::io::Result::Ok(())
+        };
+        let code = match self.escalation {
+            Some(escalation) => {
+                escalation::exec(self.session, escalation, command, stdin, collect).await?
+            }
+            None => self.session.exec(command, stdin, collect).await?,
+        };
+        output.code = code;
+        Ok(output)
+    }
+
+    /// Staging directory, created with `mktemp -d` so only the login user can
+    /// get into it
+    async fn staging(&self) -> anyhow::Result<&str> {
+        let dir = self
+            .staging
+            .get_or_try_init(|| async {
+                let mut output = Output::default();
+                let code = self
+                    .session
+                    .exec("mktemp -d /tmp/.asd-XXXXXXXXXX", None, |stderr, data| {
+                        match stderr {
+                            true => output.stderr.extend_from_slice(data),
+                            false => output.stdout.extend_from_slice(data),
+                        }
+                        std::io::Result::Ok(())
+                    })
+                    .await?;
+                let dir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                if code != Some(0) || dir.is_empty() {
+                    bail!(anyhow!(
+                        "unable to create a staging directory: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+                debug!("staging uploads in {dir}");
+                Ok(dir)
+            })
+            .await?;
+        Ok(dir)
+    }
+
+    /// Remove the staging directory if it was created
+    async fn remove_staging(&self) {
+        let Some(dir) = self.staging.get() else {
+            return;
+        };
+        let command = format!("rm -rf -- {}", quote(dir));
+        match self
+            .session
+            .exec(&command, None, |_, _| std::io::Result::Ok(()))
+            .await
+        {
+            Result::Ok(Some(0)) => debug!("removed staging directory {dir}"),
+            Result::Ok(code) => warn!("unable to remove {dir}, exit code {code:?}"),
+            Err(error) => warn!("unable to remove {dir}: {error}"),
+        }
+    }
+
+    /// Run a command that changes the remote, failing with its output
+    async fn change(&self, command: &str) -> anyhow::Result<()> {
+        self.feed(command, None).await
+    }
+
+    /// Run a command that changes the remote reading `stdin`, failing with its output
+    async fn feed(&self, command: &str, stdin: Option<&[u8]>) -> anyhow::Result<()> {
+        let output = self.run(command, stdin).await?;
+        if output.code != Some(0) {
+            let mut message = output.stderr;
+            message.extend_from_slice(&output.stdout);
+            bail!(anyhow!(
+                "{command} failed: {}",
+                String::from_utf8_lossy(&message).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Destination entry, `None` if missing
+    async fn entry(&self, path: &str) -> Option<Kind> {
+        let metadata = self.sftp.symlink_metadata(path).await.ok()?;
+        let file_type = metadata.file_type();
+        let mode = metadata.permissions.unwrap_or_default() & 0o7777;
+        if file_type.is_symlink() {
+            let target = self.sftp.read_link(path).await.ok()?;
+            Some(Kind::Link { target })
+        } else if file_type.is_dir() {
+            Some(Kind::Dir { mode })
+        } else {
+            Some(Kind::File {
+                size: metadata.size.unwrap_or_default(),
+                mtime: metadata.mtime.unwrap_or_default(),
+                mode,
+            })
+        }
+    }
+
+    async fn create_dir(&self, path: &str, mode: u32) -> anyhow::Result<()> {
+        debug!("creating remote directory {path}");
+        match self.escalation {
+            Some(_) => {
+                let path = quote(path);
+                self.change(&format!("mkdir -p -- {path} && chmod {mode:o} -- {path}"))
+                    .await
+            }
+            None => {
+                self.sftp.create_dir(path).await?;
+                let attributes = FileAttributes {
+                    permissions: Some(mode),
+                    ..FileAttributes::empty()
+                };
+                Ok(self.sftp.set_metadata(path, attributes).await?)
+            }
+        }
+    }
+
+    async fn symlink(&self, target: &str, link: &str) -> anyhow::Result<()> {
+        debug!("linking {link} to {target}");
+        match self.escalation {
+            Some(_) => {
+                self.change(&format!("ln -sfn -- {} {}", quote(target), quote(link)))
+                    .await
+            }
+            None => {
+                if self.sftp.symlink_metadata(link).await.is_ok() {
+                    self.sftp.remove_file(link).await?;
+                }
+                // OpenSSH takes the link target first, against the SFTP draft order
+                Ok(self.sftp.symlink(target, link).await?)
+            }
+        }
+    }
+
+    async fn remove(&self, path: &str, dir: bool) -> anyhow::Result<()> {
+        debug!("deleting remote {path}");
+        match (self.escalation, dir) {
+            (Some(_), true) => self.change(&format!("rmdir -- {}", quote(path))).await,
+            (Some(_), false) => self.change(&format!("rm -f -- {}", quote(path))).await,
+            (None, true) => Ok(self.sftp.remove_dir(path).await?),
+            (None, false) => Ok(self.sftp.remove_file(path).await?),
+        }
+    }
+
+    /// Remove remote partial files of `target` left by uploads of other versions
+    /// of the source, which would never be resumed
+    async fn remove_stale_parts(&self, target: &str, part: &str) -> anyhow::Result<()> {
+        let name = file_name(target)?;
+        let dir = match target.rfind('/') {
+            Some(0) => "/",
+            Some(end) => &target[..end],
+            None => ".",
+        };
+        for entry in self.sftp.read_dir(dir).await? {
+            let entry = entry.file_name();
+            if entry != file_name(part)? && is_part_of(&entry, name) {
+                let path = remote_join(dir, &entry);
+                debug!("removing stale partial file {path}");
+                self.sftp.remove_file(&path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload a file keeping its permissions and modification time. Data goes
+    /// to a partial file first, resumed if a previous upload left one, and
+    /// renamed into place once complete. With root as become user the partial
+    /// file is in the staging directory, never resumed, and copied into place as
+    /// root, other become users are sent the data through stdin. Templates are
+    /// rendered before sending and never resumed. Returns the bytes sent and
+    /// whether the upload was resumed.
+    async fn upload(
+        &self,
+        file: &Path,
+        target: &str,
+        (size, mode, mtime): (u64, u32, u32),
+        verify: bool,
+        template: Option<&BTreeMap<String, String>>,
+    ) -> anyhow::Result<(u64, bool)> {
+        let rendered = match template {
+            Some(vars) => {
+                let data = fs::read(file)
+                    .await
+                    .map_err(|error| anyhow!("unable to read template {file:?}: {error}"))?;
+                match String::from_utf8(data) {
+                    Result::Ok(text) => {
+                        let rendered = template::render(&text, vars)
+                            .map_err(|error| anyhow!("unable to render {file:?}: {error}"))?;
+                        self.progress.skip(size);
+                        self.progress.expect(rendered.len() as u64);
+                        Some(rendered.into_bytes())
+                    }
+                    Err(_) => {
+                        debug!("sending {file:?} as is, it is not UTF-8");
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let size = rendered.as_ref().map_or(size, |x| x.len() as u64);
+        if self.escalation.is_some_and(|x| x.user != "root") {
+            let data = match rendered {
+                Some(data) => data,
+                None => fs::read(file).await?,
+            };
+            self.write(&data, target, (mode, mtime), verify).await?;
+            return Ok((size, false));
+        }
+        let part = match self.escalation {
+            Some(_) => format!("{}/{}{PART}", self.staging().await?, file_name(target)?),
+            None => part_path(target, size, mtime),
+        };
+        let offset = match self.sftp.metadata(&part).await {
+            Result::Ok(metadata) if rendered.is_none() && self.escalation.is_none() => {
+                metadata.size.filter(|x| *x <= size).unwrap_or(0)
+            }
+            _ => 0,
+        };
+        let flags = match (offset, self.escalation) {
+            // a file already in the staging directory was not written by this upload
+            (_, Some(_)) => OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::EXCLUDE,
+            (0, None) => OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE,
+            (_, None) => OpenFlags::CREATE | OpenFlags::WRITE,
+        };
+        if offset == 0 && self.escalation.is_none() {
+            if let Err(error) = self.remove_stale_parts(target, &part).await {
+                warn!("unable to remove stale partial files of {target}: {error}");
+            }
+        }
+        match offset {
+            0 => debug!("uploading {file:?} to {part}"),
+            _ => info!("resuming upload of {file:?} to {part} from byte {offset}"),
+        }
+        let mut remote = self.sftp.open_with_flags(&part, flags).await?;
+        remote.seek(SeekFrom::Start(offset)).await?;
+        self.progress.file(target, size, offset);
+        let sent = match &rendered {
+            Some(data) => copy(&mut data.as_slice(), &mut remote, self.progress).await?,
+            None => {
+                let mut local = fs::File::open(file).await?;
+                local.seek(SeekFrom::Start(offset)).await?;
+                copy(&mut local, &mut remote, self.progress).await?
+            }
+        };
+        remote.shutdown().await?;
+        self.progress.end_file();
+        if verify {
+            let checksums = remote_checksums(self, std::slice::from_ref(&part)).await?;
+            let expected = match &rendered {
+                Some(data) => Some(format!("{:x}", Sha256::digest(data))),
+                None => local_checksum(file).await,
+            };
+            if checksums.get(&part) != expected.as_ref() {
+                self.sftp.remove_file(&part).await?;
+                bail!(anyhow!(
+                    "checksum mismatch uploading {target}, removed {part}"
+                ));
+            }
+            debug!("verified checksum of {part}");
+        }
+        let permissions = match self.escalation {
+            // only root copies the file out of the temporary path
+            Some(_) => 0o600,
+            None => mode,
+        };
+        let attributes = FileAttributes {
+            permissions: Some(permissions),
+            atime: Some(mtime),
+            mtime: Some(mtime),
+            ..FileAttributes::empty()
+        };
+        self.sftp.set_metadata(&part, attributes).await?;
+        debug!("moving {part} to {target}");
+        if self.escalation.is_some() {
+            let command = format!(
+                "cp -- {part} {target} && chmod {mode:o} -- {target} && touch -r {part} -- {target}",
+                part = quote(&part),
+                target = quote(target)
+            );
+            self.change(&command).await?;
+            self.sftp.remove_file(&part).await?;
+        } else {
+            // SFTP rename does not replace existing files
+            if self.sftp.symlink_metadata(target).await.is_ok() {
+                self.sftp.remove_file(target).await?;
+            }
+            self.sftp.rename(&part, target).await?;
+        }
+        Ok((sent, offset > 0))
+    }
+
+    /// Write a file as a become user other than root through the stdin of the
+    /// command, as that user can not read files left by the login user without
+    /// making them readable by everyone. The whole file is held in memory.
+    async fn write(
+        &self,
+        data: &[u8],
+        target: &str,
+        (mode, mtime): (u32, u32),
+        verify: bool,
+    ) -> anyhow::Result<()> {
+        debug!("writing {target} through stdin");
+        let time = chrono::DateTime::from_timestamp(mtime as i64, 0)
+            .ok_or_else(|| anyhow!("invalid modification time {mtime}"))?
+            .format("%Y%m%d%H%M.%S");
+        let command = format!(
+            "cat > {target} && chmod {mode:o} -- {target} && TZ=UTC0 touch -t {time} -- {target}",
+            target = quote(target)
+        );
+        self.progress.file(target, data.len() as u64, 0);
+        self.feed(&command, Some(data)).await?;
+        self.progress.add(data.len() as u64);
+        self.progress.end_file();
+        if verify {
+            let checksums = remote_checksums(self, &[target.to_string()]).await?;
+            if checksums.get(target) != Some(&format!("{:x}", Sha256::digest(data))) {
+                bail!(anyhow!("checksum mismatch uploading {target}"));
+            }
+            debug!("verified checksum of {target}");
+        }
+        Ok(())
+    }
+}
+
+/// SHA-256 of remote files through `sha256sum`, files that can not be read are left out
+async fn remote_checksums(
+    remote: &Remote<'_>,
+    paths: &[String],
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut checksums = HashMap::new();
+    for batch in paths.chunks(CHECKSUM_BATCH) {
+        let arguments = batch.iter().map(|x| quote(x)).collect::<Vec<String>>();
+        let output = remote
+            .run(&format!("sha256sum -- {}", arguments.join(" ")), None)
+            .await?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((checksum, path)) = line.split_once("  ") {
+                checksums.insert(path.to_string(), checksum.to_string());
+            }
+        }
+    }
+    Ok(checksums)
+}
+
+/// Whether a file matches its destination by size and modification time
+fn unchanged(source: &Kind, dest: Option<&Kind>, checksum: bool) -> bool {
+    match (source, dest) {
+        (
+            Kind::File { size, mtime, .. },
+            Some(Kind::File {
+                size: dest_size,
+                mtime: dest_mtime,
+                ..
+            }),
+        ) => size == dest_size && (checksum || mtime == dest_mtime),
+        _ => false,
+    }
+}
+
+/// Upload local files and directories into the remote directory `dest`,
+/// keeping their permissions and modification times. With `escalation` files
+/// are written by the become user.
+pub async fn put(
+    session: &Session,
+    sftp: &SftpSession,
+    files: &[PathBuf],
+    dest: &str,
+    escalation: Option<&Become>,
+    options: &Options,
+    progress: &Tracker,
+) -> anyhow::Result<Summary> {
+    let remote = Remote {
+        session,
+        sftp,
+        escalation,
+        progress,
+        staging: OnceCell::new(),
+    };
+    let result = put_files(&remote, files, dest, options).await;
+    remote.remove_staging().await;
+    result
+}
+
+async fn put_files(
+    remote: &Remote<'_>,
+    files: &[PathBuf],
+    dest: &str,
+    options: &Options,
+) -> anyhow::Result<Summary> {
+    let (sftp, progress) = (remote.sftp, remote.progress);
+    let mut summary = Summary::default();
+    for file in files {
+        let file = &local_source(file).await?;
+        let entries = local_tree(file, options).await?;
+        let parent = file.parent().unwrap_or(Path::new(""));
+        progress.expect(expected(&entries));
+        let mut existing = HashMap::new();
+        for entry in &entries {
+            let target = remote_join(dest, &entry.relative);
+            existing.insert(target.clone(), remote.entry(&target).await);
+        }
+        // with checksums only files of equal size are compared
+        let mut checksums = HashMap::new();
+        if options.skip_unchanged && options.checksum {
+            let candidates = entries
+                .iter()
+                .map(|x| (x, remote_join(dest, &x.relative)))
+                .filter(|(x, target)| unchanged(&x.kind, existing[target].as_ref(), true))
+                .map(|(_, target)| target)
+                .collect::<Vec<String>>();
+            checksums = remote_checksums(remote, &candidates).await?;
+        }
+        for entry in &entries {
+            let target = remote_join(dest, &entry.relative);
+            let current = existing[&target].as_ref();
+            match &entry.kind {
+                Kind::Dir { mode } => {
+                    if !matches!(current, Some(Kind::Dir { .. })) {
+                        remote.create_dir(&target, *mode).await?;
+                    }
+                }
+                Kind::Link { target: link } => {
+                    if current != Some(&entry.kind) {
+                        remote.symlink(link, &target).await?;
+                    }
+                }
+                Kind::File { size, mode, mtime } => {
+                    let path = parent.join(&entry.relative);
+                    if options.skip_unchanged && unchanged(&entry.kind, current, options.checksum) {
+                        let same = match options.checksum {
+                            true => checksums.get(&target).cloned() == local_checksum(&path).await,
+                            false => true,
+                        };
+                        if same {
+                            debug!("skipping unchanged {target}");
+                            summary.unchanged += 1;
+                            progress.skip(*size);
+                            continue;
+                        }
+                    }
+                    // writing through an existing link would change the file it points to
+                    if matches!(current, Some(Kind::Link { .. })) {
+                        remote.remove(&target, false).await?;
+                    }
+                    let (sent, resumed) = remote
+                        .upload(
+                            &path,
+                            &target,
+                            (*size, *mode, *mtime),
+                            options.verify,
+                            options.template(&entry.relative),
+                        )
+                        .await?;
+                    summary.bytes += sent;
+                    summary.resumed += resumed as u64;
+                    summary.files += 1;
+                }
+            }
+        }
+        if options.delete
+            && matches!(
+                entries.first(),
+                Some(Entry {
+                    kind: Kind::Dir { .. },
+                    ..
+                })
+            )
+        {
+            let root = remote_join(dest, &entries[0].relative);
+            let sources = entries
+                .iter()
+                .map(|x| x.relative.clone())
+                .collect::<HashSet<String>>();
+            let existing = remote_tree(sftp, &root, &mirror(options)).await?;
+            for entry in deletions(&existing, &sources, options) {
+                let target = remote_join(dest, &entry.relative);
+                remote
+                    .remove(&target, matches!(entry.kind, Kind::Dir { .. }))
+                    .await?;
+                summary.deleted += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Options walking a destination tree to mirror, excluded entries are listed so
+/// they can be kept
+fn mirror(options: &Options) -> Options {
+    Options {
+        links: Links::Preserve,
+        include: Vec::new(),
+        exclude: Vec::new(),
+        ..options.clone()
+    }
+}
+
+/// Entries of the destination tree `existing` missing from the source, children
+/// before their parents. Excluded entries are kept along with their parents, which
+/// could not be removed while holding them.
+fn deletions<'a>(
+    existing: &'a [Entry],
+    sources: &HashSet<String>,
+    options: &Options,
+) -> Vec<&'a Entry> {
+    // a path is excluded if it or any of its parents is, as when walking the source
+    let excluded = |relative: &str| {
+        relative
+            .match_indices('/')
+            .map(|(end, _)| &relative[..end])
+            .chain([relative])
+            .any(|x| options.excluded(x))
+    };
+    let mut kept = HashSet::new();
+    for entry in existing {
+        if sources.contains(&entry.relative) || excluded(&entry.relative) {
+            let mut path = entry.relative.as_str();
+            kept.insert(path);
+            while let Some((parent, _)) = path.rsplit_once('/') {
+                kept.insert(parent);
+                path = parent;
+            }
+        }
+    }
+    existing
+        .iter()
+        .rev()
+        .filter(|x| !kept.contains(x.relative.as_str()))
+        .collect()
+}
+
+/// Bytes of the files in `entries`
+fn expected(entries: &[Entry]) -> u64 {
+    entries
+        .iter()
+        .map(|x| match x.kind {
+            Kind::File { size, .. } => size,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Copy `reader` into `writer` reporting the bytes moved to `progress`
+async fn copy(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    progress: &Tracker,
+) -> io::Result<u64> {
+    let mut buffer = vec![0; COPY_BUFFER];
+    let mut copied = 0;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return io::Result::Ok(copied);
+        }
+        writer.write_all(&buffer[..read]).await?;
+        copied += read as u64;
+        progress.add(read as u64);
+    }
+}
+
+/// Local destination entry, `None` if missing
+async fn local_entry(path: &Path) -> Option<Kind> {
+    let metadata = fs::symlink_metadata(path).await.ok()?;
+    let mode = metadata.permissions().mode() & 0o7777;
+    if metadata.is_symlink() {
+        let target = fs::read_link(path).await.ok()?;
+        Some(Kind::Link {
+            target: target.to_string_lossy().to_string(),
+        })
+    } else if metadata.is_dir() {
+        Some(Kind::Dir { mode })
+    } else {
+        Some(Kind::File {
+            size: metadata.len(),
+            mtime: seconds(metadata.modified().ok()?),
+            mode,
+        })
+    }
+}
+
+/// Download a remote file keeping its permissions and modification time,
+/// through a partial file like uploads. Returns the bytes received and whether
+/// the download was resumed.
+async fn download(
+    remote: &Remote<'_>,
+    file: &str,
+    target: &Path,
+    (size, mode, mtime): (u64, u32, u32),
+    verify: bool,
+) -> anyhow::Result<(u64, bool)> {
+    let part = PathBuf::from(part_path(&target.to_string_lossy(), size, mtime));
+    let offset = match fs::metadata(&part).await {
+        Result::Ok(metadata) if metadata.len() <= size => metadata.len(),
+        _ => 0,
+    };
+    if offset == 0 {
+        if let Err(error) = remove_stale_parts(target, &part).await {
+            warn!("unable to remove stale partial files of {target:?}: {error}");
+        }
+    }
+    match offset {
+        0 => debug!("downloading {file} to {part:?}"),
+        _ => info!("resuming download of {file} to {part:?} from byte {offset}"),
+    }
+    let mut source = remote.sftp.open(file).await?;
+    let mut local = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(offset == 0)
+        .open(&part)
+        .await?;
+    source.seek(SeekFrom::Start(offset)).await?;
+    local.seek(SeekFrom::Start(offset)).await?;
+    remote.progress.file(file, size, offset);
+    let received = copy(&mut source, &mut local, remote.progress).await?;
+    local.flush().await?;
+    remote.progress.end_file();
+    if verify {
+        let checksums = remote_checksums(remote, &[file.to_string()]).await?;
+        if checksums.get(file) != local_checksum(&part).await.as_ref() {
+            fs::remove_file(&part).await?;
+            bail!(anyhow!(
+                "checksum mismatch downloading {file}, removed {part:?}"
+            ));
+        }
+        debug!("verified checksum of {part:?}");
+    }
+    local
+        .set_permissions(PermissionsExt::from_mode(mode))
+        .await?;
+    let local = local.into_std().await;
+    local.set_modified(UNIX_EPOCH + Duration::from_secs(mtime as u64))?;
+    debug!("moving {part:?} to {target:?}");
+    fs::rename(&part, target).await?;
+    Ok((received, offset > 0))
+}
+
+/// Download remote files and directories into the local directory `dest`,
+/// keeping their permissions and modification times
+pub async fn get(
+    session: &Session,
+    sftp: &SftpSession,
+    files: &[PathBuf],
+    dest: &Path,
+    options: &Options,
+    progress: &Tracker,
+) -> anyhow::Result<Summary> {
+    fs::create_dir_all(dest).await?;
+    let remote = Remote {
+        session,
+        sftp,
+        escalation: None,
+        progress,
+        staging: OnceCell::new(),
+    };
+    let mut summary = Summary::default();
+    for file in files {
+        let file = file.to_string_lossy().trim_end_matches('/').to_string();
+        let entries = remote_tree(sftp, &file, options).await?;
+        let parent = match file.rfind('/') {
+            Some(0) => "/",
+            Some(end) => &file[..end],
+            None => "",
+        };
+        progress.expect(expected(&entries));
+        let mut existing = HashMap::new();
+        for entry in &entries {
+            let target = dest.join(&entry.relative);
+            existing.insert(entry.relative.clone(), local_entry(&target).await);
+        }
+        let mut checksums = HashMap::new();
+        if options.skip_unchanged && options.checksum {
+            let candidates = entries
+                .iter()
+                .filter(|x| unchanged(&x.kind, existing[&x.relative].as_ref(), true))
+                .map(|x| remote_join(parent, &x.relative))
+                .collect::<Vec<String>>();
+            checksums = remote_checksums(&remote, &candidates).await?;
+        }
+        for entry in &entries {
+            let target = dest.join(&entry.relative);
+            let current = existing[&entry.relative].as_ref();
+            let path = remote_join(parent, &entry.relative);
+            match &entry.kind {
+                Kind::Dir { mode } => {
+                    if !matches!(current, Some(Kind::Dir { .. })) {
+                        debug!("creating directory {target:?}");
+                        fs::create_dir_all(&target).await?;
+                    }
+                    fs::set_permissions(&target, PermissionsExt::from_mode(*mode)).await?;
+                }
+                Kind::Link { target: link } => {
+                    if current != Some(&entry.kind) {
+                        debug!("linking {target:?} to {link}");
+                        if current.is_some() {
+                            fs::remove_file(&target).await?;
+                        }
+                        fs::symlink(link, &target).await?;
+                    }
+                }
+                Kind::File { size, mode, mtime } => {
+                    if options.skip_unchanged && unchanged(&entry.kind, current, options.checksum) {
+                        let same = match options.checksum {
+                            true => checksums.get(&path).cloned() == local_checksum(&target).await,
+                            false => true,
+                        };
+                        if same {
+                            debug!("skipping unchanged {target:?}");
+                            summary.unchanged += 1;
+                            progress.skip(*size);
+                            continue;
+                        }
+                    }
+                    if matches!(current, Some(Kind::Link { .. })) {
+                        fs::remove_file(&target).await?;
+                    }
+                    let (received, resumed) = download(
+                        &remote,
+                        &path,
+                        &target,
+                        (*size, *mode, *mtime),
+                        options.verify,
+                    )
+                    .await?;
+                    summary.bytes += received;
+                    summary.resumed += resumed as u64;
+                    summary.files += 1;
+                }
+            }
+        }
+        if options.delete
+            && matches!(
+                entries.first(),
+                Some(Entry {
+                    kind: Kind::Dir { .. },
+                    ..
+                })
+            )
+        {
+            let root = dest.join(&entries[0].relative);
+            let sources = entries
+                .iter()
+                .map(|x| x.relative.clone())
+                .collect::<HashSet<String>>();
+            let existing = local_tree(&root, &mirror(options)).await?;
+            for entry in deletions(&existing, &sources, options) {
+                let target = dest.join(&entry.relative);
+                debug!("deleting {target:?}");
+                match entry.kind {
+                    Kind::Dir { .. } => fs::remove_dir(&target).await?,
+                    _ => fs::remove_file(&target).await?,
+                }
+                summary.deleted += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_files() {
+        assert!(is_part_of(
+            &part_path("app.tar", 0x1f00, 0x65a1b2c3),
+            "app.tar"
+        ));
+        assert!(is_part_of("app.tar.0-0.asd-part", "app.tar"));
+        assert!(!is_part_of("app.tar.asd-part", "app.tar"));
+        assert!(!is_part_of("app.tar.gz.10-20.asd-part", "app.tar"));
+        assert!(!is_part_of("app.tar.10-20.asd-part", "app"));
+        assert!(!is_part_of("app.tar.10-2x.asd-part", "app.tar"));
+    }
+}